@@ -0,0 +1,144 @@
+//! Admission of the requests that spawn a process: at most `maxprocesses`
+//! processes run at once, and at most `maxconcurrent` per route. Requests over
+//! the caps wait in a bounded FIFO queue (`queuesize`) up to `queuetimeout`;
+//! when the queue is full, or the wait expires, the client gets 503.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+
+use crate::serviceconf::ServiceConf;
+
+pub const DEFAULT_QUEUE_SIZE: usize = 64;
+/// max wait in the queue, in ms
+pub const DEFAULT_QUEUE_TIMEOUT: u64 = 10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refused {
+    QueueFull,
+    QueueTimeout,
+}
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refused::QueueFull => write!(f, "too many requests, the queue is full"),
+            Refused::QueueTimeout => write!(f, "too many requests, queue timeout"),
+        }
+    }
+}
+
+/// The right to run a process, released when dropped
+#[derive(Debug, Default)]
+pub struct Permit {
+    _permits: Vec<OwnedSemaphorePermit>,
+    /// time spent in the queue, in ms
+    pub waited_ms: u64,
+    /// the requests already queued when this one arrived
+    pub queue_depth: usize,
+}
+
+#[derive(Clone)]
+pub struct Admission {
+    global: Option<Arc<Semaphore>>,
+    routes: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+    queued: Arc<AtomicUsize>,
+    queuesize: usize,
+    queuetimeout: u64,
+}
+
+impl Admission {
+    pub fn new(conf: &ServiceConf) -> Self {
+        Admission {
+            global: conf.maxprocesses.map(|max| Arc::new(Semaphore::new(max))),
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            queued: Arc::new(AtomicUsize::new(0)),
+            queuesize: conf.queuesize.unwrap_or(DEFAULT_QUEUE_SIZE),
+            queuetimeout: conf.queuetimeout.unwrap_or(DEFAULT_QUEUE_TIMEOUT),
+        }
+    }
+
+    /// The `Retry-After` seconds suggested to refused clients
+    pub fn retry_after(&self) -> u64 {
+        self.queuetimeout.div_ceil(1000).max(1)
+    }
+
+    /// Wait for a free slot of the route (up to `maxconcurrent`) and a global one
+    pub async fn acquire(&self, route: &str, maxconcurrent: Option<usize>) -> Result<Permit, Refused> {
+        let route_sem = maxconcurrent.map(|max| {
+            self.routes.lock().unwrap()
+                .entry(route.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        });
+        let semaphores: Vec<Arc<Semaphore>> = route_sem.into_iter().chain(self.global.clone()).collect();
+        // the semaphores are fair: with queued requests there are no free permits
+        let permits: Vec<OwnedSemaphorePermit> = semaphores.iter()
+            .map_while(|sem| sem.clone().try_acquire_owned().ok())
+            .collect();
+        if permits.len() == semaphores.len() {
+            return Ok(Permit { _permits: permits, ..Default::default() });
+        }
+        drop(permits);
+        let queue_depth = self.queued.fetch_add(1, Ordering::SeqCst);
+        if queue_depth >= self.queuesize {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(Refused::QueueFull);
+        }
+        let started = Instant::now();
+        let waited = tokio::time::timeout(Duration::from_millis(self.queuetimeout), async {
+            let mut permits = Vec::new();
+            for sem in semaphores {
+                permits.push(sem.acquire_owned().await.expect("admission semaphores are never closed"));
+            }
+            permits
+        }).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        match waited {
+            Ok(permits) => Ok(Permit {
+                _permits: permits,
+                waited_ms: started.elapsed().as_millis() as u64,
+                queue_depth,
+            }),
+            Err(_) => Err(Refused::QueueTimeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn admission_queue() {
+        let conf = ServiceConf { maxprocesses: Some(2), queuesize: Some(1), queuetimeout: Some(100), ..Default::default() };
+        let admission = Admission::new(&conf);
+        assert_eq!(admission.retry_after(), 1);
+        let first = admission.acquire("GET /a", Some(1)).await.unwrap();
+        assert_eq!((first.waited_ms, first.queue_depth), (0, 0));
+        // another route still has a global slot
+        let other = admission.acquire("GET /b", None).await.unwrap();
+        // the route is busy: queued, then a full queue
+        let queued = {
+            let admission = admission.clone();
+            tokio::spawn(async move { admission.acquire("GET /a", Some(1)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(admission.acquire("GET /b", None).await.unwrap_err(), Refused::QueueFull);
+        assert_eq!(queued.await.unwrap().unwrap_err(), Refused::QueueTimeout);
+        // the queued request gets the slot when the first process ends
+        let queued = {
+            let admission = admission.clone();
+            tokio::spawn(async move { admission.acquire("GET /a", Some(1)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        let permit = queued.await.unwrap().unwrap();
+        assert!(permit.waited_ms >= 10);
+        assert_eq!(permit.queue_depth, 0);
+        drop(other);
+    }
+}
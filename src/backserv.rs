@@ -14,7 +14,7 @@ use http_body_util::{BodyExt, Full};
 
 //use http_body_util::{Full, BodyExt, StreamBody};
 
-use hyper::body::Frame;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
 use hyper::server::conn::http1;
 use hyper::service::Service;
 use hyper::{body::Incoming as IncomingBody, Request, Response};
@@ -92,38 +92,77 @@ impl<T: Clone> Svc<T> {
     }
 }
 
-fn uri_extract_req_id(uri: &hyper::Uri) -> Option<String> {
-    if uri.path().starts_with("/urhttp/") {
-        Some(uri.path().replace("/urhttp/", ""))
-    } else {
-        println!("bad news: {} ", uri.path());
-        None
+/// What the process asks for with its request
+#[derive(Debug,PartialEq)]
+enum BackCall {
+    /// `/urhttp/{req_id}`: the reply (or the last chunk of a stream)
+    Reply,
+    /// `/urhttp/{req_id}/chunk`: a chunk of a streamed reply
+    Chunk,
+    /// `/urhttp/{req_id}/extend`: push back the deadline, the body
+    /// may hold the ms to add
+    Extend,
+}
+
+fn uri_extract_req_id(uri: &hyper::Uri) -> Option<(String, BackCall)> {
+    match uri.path().strip_prefix("/urhttp/") {
+        Some(rest) => {
+            if let Some(req_id) = rest.strip_suffix("/chunk") {
+                Some((req_id.to_string(), BackCall::Chunk))
+            } else if let Some(req_id) = rest.strip_suffix("/extend") {
+                Some((req_id.to_string(), BackCall::Extend))
+            } else {
+                Some((rest.to_string(), BackCall::Reply))
+            }
+        },
+        None => {
+            println!("bad news: {} ", uri.path());
+            None
+        }
     }
 }
 
-async fn getpayload(req: Request<IncomingBody>) -> Result<serde_json::Value,serde_json::Error> {
-    let frame_stream = req.into_body().map_frame(|frame| {
-        let frame = if let Ok(data) = frame.into_data() {
-            data.iter()
-            .map(|byte| byte.to_be())
-            .collect::<Bytes>()
-        } else {
-            Bytes::new()
-        };
-        
-        Frame::data(frame)
-    }).collect();
-    //let (parts, body) = req.into_parts();
-    //let body = serde_json::from_slice(&body).unwrap();
-    let bites =  frame_stream.await.unwrap().to_bytes();
-    //println!("received {:?}", bites);
-    let str = Vec::<u8>::from(bites.as_ref());
-    let _astr = match std::str::from_utf8(&str) {
-        Ok(s) => s,
-        Err(e) => {eprintln!("err{}",e); ""}
+/// the reply status, default 200: a final status, 1xx can not be a reply
+const STATUS_HEADER: &str = "x-urocket-status";
+/// `X-Urocket-Header-<Name>: <value>` adds `<Name>: <value>` to the reply
+const HEADER_PREFIX: &str = "x-urocket-header-";
+/// computed by the front service, they can not be set by the process
+const RESERVED_HEADERS: [&str; 3] = ["content-length", "transfer-encoding", "connection"];
+
+/// Read the reply status and headers from the `X-Urocket-*` request headers
+fn reply_meta(headers: &HeaderMap) -> Result<(u32, Vec<(String,String)>), String> {
+    let code = match headers.get(STATUS_HEADER) {
+        Some(v) => match v.to_str().ok().and_then(|x| x.trim().parse::<u16>().ok()) {
+            Some(c) if (200..=599).contains(&c) => c as u32,
+            _ => return Err(format!("Invalid {}: {:?}", STATUS_HEADER, v)),
+        },
+        None => 200
     };
-    info!("received payload from back: {}",_astr);
-    serde_json::from_slice(&str)
+    let mut reply_headers = Vec::new();
+    for (name, value) in headers.iter() {
+        let name = match name.as_str().strip_prefix(HEADER_PREFIX) {
+            Some(n) => n,
+            None => continue
+        };
+        if RESERVED_HEADERS.contains(&name) {
+            return Err(format!("Header {} can not be set", name));
+        }
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!("Invalid header name {}", name));
+        }
+        let value = value.to_str().map_err(|_| format!("Invalid value for header {}", name))?;
+        if HeaderValue::from_str(value).is_err() {
+            return Err(format!("Invalid value for header {}", name));
+        }
+        reply_headers.push((name.to_string(), value.to_string()));
+    }
+    Ok((code, reply_headers))
+}
+
+async fn getpayload(req: Request<IncomingBody>) -> Result<Bytes,hyper::Error> {
+    let bites = req.into_body().collect().await?.to_bytes();
+    info!("received payload from back: {} bytes", bites.len());
+    Ok(bites)
 }
 
 impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
@@ -137,19 +176,43 @@ impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
         Box::pin(async move {
             let uri: hyper::Uri = req.uri().clone();
             match uri_extract_req_id(&uri) {
-                Some(req_id) => {
+                Some((req_id, call)) => {
+                    let (code, mut headers) = match reply_meta(req.headers()) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            warn!("bad reply for {}: {}", req_id, e);
+                            let b = Response::builder().status(400).body(Full::new(Bytes::from(format!("{}\n", e)))).unwrap();
+                            return Ok(b);
+                        }
+                    };
+                    if !headers.iter().any(|(k, _)| k == "content-type") {
+                        if let Some(ct) = req.headers().get(hyper::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+                            headers.push((String::from("content-type"), ct.to_string()));
+                        }
+                    }
                     let message = match getpayload(req).await {
-                        Ok(r) => {
-                            let payload = r;
-                            ForHttpResponse { code: 200, data: payload }
-                        },
+                        Ok(body) => ForHttpResponse { code, body, headers },
                         Err(e) => {
-                            warn!("error parsing backserv {}", e);
-                            let payload = serde_json::Value::Bool(false);
-                            ForHttpResponse { code: 500, data: payload }
+                            warn!("error reading backserv payload {}", e);
+                            let b = Response::builder().status(400).body(Full::new(Bytes::from("Could not read the payload\n"))).unwrap();
+                            return Ok(b);
+                        }
+                    };
+                    let resp = match call {
+                        BackCall::Reply => vh.push_fulfill(&req_id, message),
+                        BackCall::Chunk => vh.push_chunk(&req_id, message),
+                        BackCall::Extend => {
+                            let by_ms = String::from_utf8_lossy(&message.body).trim().to_string();
+                            if by_ms.is_empty() {
+                                vh.push_extend(&req_id, None)
+                            } else if let Ok(by_ms) = by_ms.parse::<u64>() {
+                                vh.push_extend(&req_id, Some(by_ms))
+                            } else {
+                                let b = Response::builder().status(400).body(Full::new(Bytes::from("Invalid extension, ms expected\n"))).unwrap();
+                                return Ok(b);
+                            }
                         }
                     };
-                    let resp = vh.push_fulfill(&req_id, message);
                     //let bod = req.collect().await.unwrap().to_bytes();
                     match resp.await {
                         Ok(exresp) => {
@@ -182,6 +245,50 @@ impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_req_id() {
+        let uri: hyper::Uri = "/urhttp/1234-abcd".parse().unwrap();
+        assert_eq!(uri_extract_req_id(&uri), Some((String::from("1234-abcd"), BackCall::Reply)));
+        let uri: hyper::Uri = "/urhttp/1234-abcd/chunk".parse().unwrap();
+        assert_eq!(uri_extract_req_id(&uri), Some((String::from("1234-abcd"), BackCall::Chunk)));
+        let uri: hyper::Uri = "/urhttp/1234-abcd/extend".parse().unwrap();
+        assert_eq!(uri_extract_req_id(&uri), Some((String::from("1234-abcd"), BackCall::Extend)));
+        let uri: hyper::Uri = "/other/1234-abcd".parse().unwrap();
+        assert_eq!(uri_extract_req_id(&uri), None);
+    }
+
+    #[test]
+    fn reply_status_and_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(reply_meta(&headers), Ok((200, vec![])));
+        headers.insert("x-urocket-status", "302".parse().unwrap());
+        headers.insert("x-urocket-header-location", "/pet/1".parse().unwrap());
+        headers.append("x-urocket-header-set-cookie", "a=1".parse().unwrap());
+        headers.append("x-urocket-header-set-cookie", "b=2".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        let (code, reply) = reply_meta(&headers).unwrap();
+        assert_eq!(code, 302);
+        assert_eq!(reply, vec![
+            ("location".to_string(), "/pet/1".to_string()),
+            ("set-cookie".to_string(), "a=1".to_string()),
+            ("set-cookie".to_string(), "b=2".to_string()),
+        ]);
+        headers.insert("x-urocket-status", "ok".parse().unwrap());
+        assert!(reply_meta(&headers).is_err());
+        for status in ["101", "600"] {
+            headers.insert("x-urocket-status", status.parse().unwrap());
+            assert_eq!(reply_meta(&headers), Err(format!("Invalid x-urocket-status: \"{}\"", status)));
+        }
+        headers.insert("x-urocket-status", "201".parse().unwrap());
+        headers.insert("x-urocket-header-content-length", "3".parse().unwrap());
+        assert!(reply_meta(&headers).is_err());
+    }
+}
+
 // use https://docs.rs/axum/latest/axum/routing/struct.Router.html#method.nest_service
 // and tower service:
 // https://docs.rs/tower-service/0.3.2/tower_service/trait.Service.html
@@ -1,5 +1,5 @@
 
-use tracing::info;
+use tracing::{info, error};
 use urocket_http_stage::cmdlineparser::parse;
 
 use urocket_http_stage::processcontroller::ProcessController;
@@ -38,14 +38,15 @@ async fn main() -> Result<(),()> {
         "/tmp/urocketsocket.sock"
     };
 
-    let conf = config.clone_paths();
+    let mut conf = config.clone_paths();
+    conf.debug = conf.debug || config.debug_level > 0;
 
     let pctl = toktor_new!(ProcessController);
     let requests_visor = toktor_new!(RequestsVisor, &pctl, &conf);
-    let rv = requests_visor.clone();
-    tokio::spawn(async move {
-        run_front(&rv).await;
-    });
+    if let Err(e) = run_front(&conf, &requests_visor).await {
+        error!("Could not start the front service: {}", e);
+        return Err(());
+    }
     run_backserv(socketpath, &requests_visor).await;
     println!("Hello, world!");
     Ok(())
@@ -0,0 +1,199 @@
+//! Capture of the process stdout and stderr: the pipes are drained
+//! concurrently by the tokio reactor, each one keeping up to `maxoutput`
+//! bytes. The rest is dropped, or written to a
+//! spill file (`spilloutput`), readable by the owner only and removed after
+//! `spillttl`.
+
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::sync::{mpsc, watch};
+use tracing::warn;
+
+/// default bytes kept of each stream
+pub const DEFAULT_MAX_OUTPUT: usize = 10 * 1024 * 1024;
+const READ_CHUNK: usize = 8192;
+/// a streamed stdout line longer than this is sent in pieces
+const MAX_LINE: usize = 64 * 1024;
+/// default time the spill files are kept, in ms
+pub const DEFAULT_SPILL_TTL: u64 = 600000;
+
+/// What was captured of a stream
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Captured {
+    /// the kept bytes, as written
+    pub bytes: Bytes,
+    /// the bytes written by the process
+    pub total: usize,
+    /// more than `cap` bytes were written
+    pub truncated: bool,
+    /// the file holding the bytes after the cap
+    pub spill: Option<PathBuf>,
+}
+
+/// Drain a pipe of the child: keep up to `cap` bytes, the others go to the `spill`
+/// file, if any. Each line is also sent to `lines` as soon as it is read.
+/// When `stop` turns true the pipe is left, even if still open
+pub async fn capture(pipe: std::fs::File, cap: usize, spill: Option<PathBuf>, lines: Option<mpsc::UnboundedSender<Bytes>>, mut stop: watch::Receiver<bool>) -> Captured {
+    let mut rx = match pipe::Receiver::from_file(pipe) {
+        Ok(rx) => rx,
+        Err(e) => return Captured { bytes: Bytes::from(format!("EE: {:?}", e)), ..Default::default() },
+    };
+    let mut kept = Vec::new();
+    let mut total = 0;
+    let mut spill_file: Option<tokio::fs::File> = None;
+    let mut line = Vec::new();
+    let mut chunk = vec![0u8; READ_CHUNK];
+    loop {
+        let read = tokio::select! {
+            read = rx.read(&mut chunk) => read,
+            _ = stopped(&mut stop) => {
+                warn!("capture stopped, the pipe is still open");
+                break;
+            }
+        };
+        let n = match read {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                warn!("capture read error {}", e);
+                break;
+            }
+        };
+        let data = &chunk[..n];
+        total += n;
+        let room = cap.saturating_sub(kept.len()).min(n);
+        kept.extend_from_slice(&data[..room]);
+        if room < n {
+            if let Some(path) = &spill {
+                if spill_file.is_none() {
+                    let created = tokio::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path).await;
+                    match created {
+                        Ok(f) => spill_file = Some(f),
+                        Err(e) => warn!("spill file {:?} error {}", path, e),
+                    }
+                }
+                if let Some(f) = &mut spill_file {
+                    if let Err(e) = f.write_all(&data[room..]).await {
+                        warn!("spill file {:?} error {}", path, e);
+                    }
+                }
+            }
+        }
+        if let Some(tx) = &lines {
+            line.extend_from_slice(data);
+            while let Some(pos) = line.iter().position(|b| *b == b'\n') {
+                let rest = line.split_off(pos + 1);
+                let _ = tx.send(Bytes::from(std::mem::replace(&mut line, rest)));
+            }
+            if line.len() > MAX_LINE {
+                let _ = tx.send(Bytes::from(std::mem::take(&mut line)));
+            }
+        }
+    }
+    if let (Some(tx), false) = (&lines, line.is_empty()) {
+        line.push(b'\n');
+        let _ = tx.send(Bytes::from(line));
+    }
+    if let Some(f) = &mut spill_file {
+        let _ = f.flush().await;
+    }
+    let spill = spill_file.and(spill);
+    let truncated = total > kept.len();
+    Captured { bytes: Bytes::from(kept), total, truncated, spill }
+}
+
+impl Captured {
+    /// The kept bytes as text for the logs (lossy UTF-8, a final newline
+    /// removed). A truncation is told by `truncated`, never in the text
+    pub fn text(&self) -> String {
+        let mut text = String::from_utf8_lossy(&self.bytes).to_string();
+        if text.ends_with('\n') {
+            text.pop();
+        }
+        text
+    }
+}
+
+/// Resolves when `stop` turns true, never if its sender is gone
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    if stop.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Remove the spill files after `ttl` ms
+pub fn expire_spills(spills: Vec<PathBuf>, ttl: u64) {
+    if spills.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(tokio::time::Duration::from_millis(ttl)).await;
+        for path in spills {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("spill file {:?} remove error {}", path, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::OwnedFd;
+    use std::process::{Command, Stdio};
+
+    async fn capture_of(script: &str, cap: usize, spill: Option<PathBuf>, lines: Option<mpsc::UnboundedSender<Bytes>>) -> Captured {
+        let mut child = Command::new("/bin/sh").arg("-c").arg(script).stdout(Stdio::piped()).spawn().unwrap();
+        let stdout = std::fs::File::from(OwnedFd::from(child.stdout.take().unwrap()));
+        let captured = capture(stdout, cap, spill, lines, watch::channel(false).1).await;
+        child.wait().unwrap();
+        captured
+    }
+
+    #[tokio::test]
+    async fn capture_capped() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let captured = capture_of("echo one; echo two", 100, None, Some(tx)).await;
+        assert_eq!(captured, Captured { bytes: Bytes::from("one\ntwo\n"), total: 8, truncated: false, spill: None });
+        assert_eq!(rx.recv().await.unwrap(), Bytes::from("one\n"));
+        assert_eq!(rx.recv().await.unwrap(), Bytes::from("two\n"));
+        assert_eq!(rx.recv().await, None);
+        let captured = capture_of("printf 0123456789", 4, None, None).await;
+        assert_eq!(captured.bytes, "0123");
+        assert_eq!(captured.text(), "0123");
+        assert!(captured.truncated);
+        let spill = std::env::temp_dir().join(format!("urocket-capture-{}.stdout", std::process::id()));
+        let captured = capture_of("printf 0123456789", 4, Some(spill.clone()), None).await;
+        assert_eq!(captured.spill, Some(spill.clone()));
+        assert_eq!(std::fs::read_to_string(&spill).unwrap(), "456789");
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&spill).unwrap().permissions().mode() & 0o777, 0o600);
+        expire_spills(vec![spill.clone()], 10);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert!(!spill.exists());
+        // no overflow, no spill file
+        let captured = capture_of("printf 0123", 4, Some(spill.clone()), None).await;
+        assert_eq!(captured.spill, None);
+        assert!(!spill.exists());
+    }
+
+    #[tokio::test]
+    async fn capture_stopped() {
+        // the background sleep keeps stdout open
+        let mut child = Command::new("/bin/sh").arg("-c").arg("echo early; sleep 5 & exit 0").stdout(Stdio::piped()).spawn().unwrap();
+        let stdout = std::fs::File::from(OwnedFd::from(child.stdout.take().unwrap()));
+        let (stop, stopped) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            stop.send_replace(true);
+        });
+        let started = std::time::Instant::now();
+        let captured = capture(stdout, 100, None, None, stopped).await;
+        assert_eq!(captured.text(), "early");
+        assert!(started.elapsed().as_millis() < 2000);
+        child.wait().unwrap();
+    }
+}
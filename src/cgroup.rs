@@ -0,0 +1,194 @@
+//! cgroups v2 limits of the handler processes (`limits` in `inject`).
+//! urocket owns a delegated subtree (`root`): each request gets its own
+//! child cgroup, removed when the process exits, or the processes of a route
+//! share a `group`. The child joins the cgroup before exec, so the limits
+//! apply from its first instruction.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::procenv::Limits;
+
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup/urocket";
+
+/// What the cgroup reports when the process exits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CGroupStats {
+    /// memory.peak in bytes (per-request cgroups, Linux >= 5.19)
+    pub memory_peak: Option<u64>,
+    /// processes killed by the OOM killer
+    pub oom_kills: u64,
+    /// forks refused because of pids.max
+    pub pids_max_hits: u64,
+    /// a shared `group`: the events are the ones of all its processes
+    /// while this one ran
+    pub shared: bool,
+}
+
+impl CGroupStats {
+    /// The limit that made the process fail, if any: only known for per-request
+    /// cgroups, in a shared group another process can have hit it
+    pub fn limit_hit(&self) -> Option<String> {
+        if self.shared {
+            None
+        } else if self.oom_kills > 0 {
+            Some(String::from("memory.max"))
+        } else if self.pids_max_hits > 0 {
+            Some(String::from("pids.max"))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CGroup {
+    path: PathBuf,
+    /// a per-request cgroup, removed on release
+    owned: bool,
+    /// the event counters before the process, for shared groups
+    before: CGroupStats,
+}
+
+impl CGroup {
+    /// Create the cgroup of the request (or reuse the route `group`) and write the limits
+    pub fn create(limits: &Limits, uuid: &str) -> io::Result<CGroup> {
+        let root = PathBuf::from(limits.root.as_deref().unwrap_or(DEFAULT_CGROUP_ROOT));
+        let (path, owned) = match &limits.group {
+            Some(group) if group.is_empty() || group == "." || group == ".." || group.contains('/') => {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid cgroup group {:?}", group)));
+            }
+            Some(group) => (root.join(group), false),
+            None => (root.join(format!("urocket-{}", uuid)), true),
+        };
+        enable_controllers(&root, limits)?;
+        match fs::create_dir(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && !owned => {}
+            Err(e) => return Err(e),
+        }
+        let settings = [
+            ("memory.max", limits.memory_max.as_ref().map(|v| v.to_string())),
+            ("cpu.max", limits.cpu_max.as_ref().map(|v| v.to_string())),
+            ("pids.max", limits.pids_max.as_ref().map(|v| v.to_string())),
+            ("io.weight", limits.io_weight.map(|v| v.to_string())),
+        ];
+        for (file, value) in settings {
+            if let Some(value) = value {
+                if let Err(e) = fs::write(path.join(file), &value) {
+                    if owned {
+                        let _ = fs::remove_dir(&path);
+                    }
+                    return Err(io::Error::new(e.kind(), format!("{} {}: {}", file, value, e)));
+                }
+            }
+        }
+        let before = read_stats(&path);
+        Ok(CGroup { path, owned, before })
+    }
+
+    /// The `cgroup.procs` file: the child writes `0` in it to join the cgroup
+    pub fn procs(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    /// Read back the peak memory and the limit events, then remove the per-request cgroup
+    pub fn release(self) -> CGroupStats {
+        let after = read_stats(&self.path);
+        let stats = CGroupStats {
+            memory_peak: if self.owned { after.memory_peak } else { None },
+            oom_kills: after.oom_kills.saturating_sub(self.before.oom_kills),
+            pids_max_hits: after.pids_max_hits.saturating_sub(self.before.pids_max_hits),
+            shared: !self.owned,
+        };
+        if self.owned {
+            if let Err(e) = fs::remove_dir(&self.path) {
+                warn!("cgroup {:?} not removed: {}", self.path, e);
+            }
+        }
+        stats
+    }
+}
+
+/// Enable in the root subtree the controllers the limits need
+fn enable_controllers(root: &Path, limits: &Limits) -> io::Result<()> {
+    let control = root.join("cgroup.subtree_control");
+    let enabled = fs::read_to_string(&control).unwrap_or_default();
+    let needed = [
+        ("memory", limits.memory_max.is_some()),
+        ("cpu", limits.cpu_max.is_some()),
+        ("pids", limits.pids_max.is_some()),
+        ("io", limits.io_weight.is_some()),
+    ];
+    for (controller, _) in needed.iter().filter(|(c, used)| *used && !enabled.split_whitespace().any(|e| e == *c)) {
+        fs::write(&control, format!("+{}", controller))
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot enable {} in {:?}: {}", controller, root, e)))?;
+    }
+    Ok(())
+}
+
+/// `key value` lines of a cgroup events file
+fn read_event(path: &Path, key: &str) -> u64 {
+    fs::read_to_string(path).unwrap_or_default().lines()
+        .filter_map(|l| l.split_once(' '))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn read_stats(path: &Path) -> CGroupStats {
+    CGroupStats {
+        memory_peak: fs::read_to_string(path.join("memory.peak")).ok().and_then(|v| v.trim().parse().ok()),
+        oom_kills: read_event(&path.join("memory.events"), "oom_kill"),
+        pids_max_hits: read_event(&path.join("pids.events"), "max"),
+        shared: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procenv::LimitValue;
+
+    #[test]
+    fn cgroup_files() {
+        // a plain directory stands for the delegated subtree
+        let root = std::env::temp_dir().join(format!("urocket-cgtest-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let limits = Limits {
+            memory_max: Some(LimitValue::Text(String::from("64M"))),
+            pids_max: Some(LimitValue::Number(16)),
+            root: Some(root.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let cg = CGroup::create(&limits, "R1").unwrap();
+        let path = root.join("urocket-R1");
+        assert_eq!(cg.procs(), path.join("cgroup.procs"));
+        assert_eq!(fs::read_to_string(path.join("memory.max")).unwrap(), "64M");
+        assert_eq!(fs::read_to_string(path.join("pids.max")).unwrap(), "16");
+        assert!(!path.join("cpu.max").exists());
+        fs::write(path.join("memory.peak"), "1048576\n").unwrap();
+        fs::write(path.join("memory.events"), "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\n").unwrap();
+        let stats = cg.release();
+        assert_eq!(stats.memory_peak, Some(1048576));
+        assert_eq!(stats.oom_kills, 1);
+        assert_eq!(stats.limit_hit(), Some(String::from("memory.max")));
+        // a shared group counts only the new events, not blamed on this process
+        for group in ["../escape", "a/b", ".."] {
+            let limits = Limits { group: Some(String::from(group)), ..limits.clone() };
+            assert_eq!(CGroup::create(&limits, "R3").unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        let limits = Limits { group: Some(String::from("reports")), ..limits };
+        fs::create_dir_all(root.join("reports")).unwrap();
+        fs::write(root.join("reports/pids.events"), "max 2\n").unwrap();
+        let cg = CGroup::create(&limits, "R2").unwrap();
+        fs::write(root.join("reports/pids.events"), "max 3\n").unwrap();
+        let stats = cg.release();
+        assert_eq!(stats, CGroupStats { memory_peak: None, oom_kills: 0, pids_max_hits: 1, shared: true });
+        assert_eq!(stats.limit_hit(), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -25,11 +25,12 @@ use bytes::Bytes;
 //use axum::body::Full;
 use hyper::StatusCode;
 //use http_body_util::{combinators::BoxBody, BodyExt, Empty, BodyExt, StreamBody};
-use http_body_util::Full;
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 
 use tracing::{info, span, warn, Level};
 
-//use hyper::body::Frame;
+use hyper::body::Frame;
 use hyper::server::conn::http1;
 use hyper::service::Service;
 use hyper::{body::Incoming as IncomingBody, Request, Response};
@@ -37,7 +38,7 @@ use tokio::net::TcpListener;
 use hyper_util::rt::TokioIo;
 
 use std::future::Future;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 //use std::simd::SimdConstPtr;
 
@@ -49,17 +50,50 @@ use std::pin::Pin;
 use crate::requestsvisor::FrontResponse;
 use crate::requestsvisor::RequestsVisor;
 use crate::restmessage::RestMessage;
+use crate::serviceconf::ServiceConf;
 
-pub async fn run_front(arbiter: &RequestsVisor) {
+/// Bind the front service on `conf.bind` addresses (all IPv4 interfaces
+/// if none is configured) and `conf.port`, then accept connections, one task
+/// for each listener.
+/// With `port: 0` the first address gets an ephemeral port, the others
+/// are bound on the same port.
+/// Returns the bound addresses.
+pub async fn run_front(conf: &ServiceConf, arbiter: &RequestsVisor) -> std::io::Result<Vec<SocketAddr>> {
     span!(Level::WARN, "frontrun");
     span!(Level::INFO, "frontrun");
-    // let db = Db::default();
-    let addr: SocketAddr = ([0, 0, 0, 0], 8080).into();
+    let ips = if conf.bind.is_empty() {
+        vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)]
+    } else {
+        conf.bind.clone()
+    };
+    let mut port = conf.port;
+    let mut listeners = Vec::new();
+    for ip in ips {
+        let listener = TcpListener::bind(SocketAddr::new(ip, port)).await?;
+        let addr = listener.local_addr()?;
+        port = addr.port();
+        info!("Listening on http://{}", addr);
+        listeners.push((listener, addr));
+    }
+    let addrs = listeners.iter().map(|(_, addr)| *addr).collect();
+    for (listener, _) in listeners {
+        let arbiter = arbiter.clone();
+        tokio::spawn(async move {
+            accept_front(listener, &arbiter).await;
+        });
+    }
+    Ok(addrs)
+}
 
-    let listener = TcpListener::bind(addr).await.unwrap();
-    info!("Listening on http://{}", addr);
+async fn accept_front(listener: TcpListener, arbiter: &RequestsVisor) {
     loop {
-        let (stream, socket) = listener.accept().await.unwrap();
+        let (stream, socket) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept connection: {:?}", e);
+                continue;
+            }
+        };
         let io = TokioIo::new(stream);
 
         let svc = Svc::new(socket, arbiter);
@@ -79,6 +113,13 @@ pub async fn run_front(arbiter: &RequestsVisor) {
     }
 }
 
+/// the error aborts a streamed reply
+type FrontBody = BoxBody<Bytes, std::io::Error>;
+
+fn full<T: Into<Bytes>>(chunk: T) -> FrontBody {
+    Full::new(chunk.into()).map_err(|never| match never {}).boxed()
+}
+
 #[derive(Clone)]
 struct Svc<T> {
     socket: SocketAddr,
@@ -92,7 +133,7 @@ impl<T: Clone> Svc<T> {
 }
 
 impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
-    type Response = Response<Full<Bytes>>;
+    type Response = Response<FrontBody>;
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
     
@@ -103,7 +144,7 @@ impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
             
             info!("receiving from {}:{}", si.ip(), si.port());
 
-            let rmsg = RestMessage::parse_incoming(req).await;
+            let rmsg = RestMessage::parse_incoming(req, si).await;
             let visormsg = vh.wait_for(rmsg);
             let (rx, req_id) = match visormsg.await {
                 Ok((x, y)) => {
@@ -124,15 +165,81 @@ impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
                         FrontResponse::BackMsg(exresp) => {
                             //serde_json::to_string(value)
                             let status = StatusCode::from_u16(exresp.code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-                            let response = serde_json::to_string(&exresp.data).unwrap();
-                            let a = Response::builder().status(status).body(Full::new(Bytes::from(response))).unwrap();
+                            let mut builder = Response::builder().status(status);
+                            for (k, v) in exresp.headers.iter() {
+                                builder = builder.header(k, v);
+                            }
+                            let a = builder.body(full(exresp.body)).unwrap();
                             //hresp = Response::builder().body(a)
                             Ok(a)
                         }
+                        FrontResponse::Output { code, content_type, headers, body } => {
+                            let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                            let mut builder = Response::builder()
+                                .status(status)
+                                .header(hyper::header::CONTENT_TYPE, content_type);
+                            for (k, v) in headers.iter() {
+                                builder = builder.header(k, v);
+                            }
+                            let a = builder.body(full(body)).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::Stream { code, headers, chunks } => {
+                            let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                            let mut builder = Response::builder().status(status);
+                            for (k, v) in headers.iter() {
+                                builder = builder.header(k, v);
+                            }
+                            let frames = ReceiverStream::new(chunks).map(|chunk| chunk.map(Frame::data));
+                            let a = builder.body(StreamBody::new(frames).boxed()).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::Accepted(id) => {
+                            let body = serde_json::json!({"id": id, "status": "pending"}).to_string();
+                            let a = Response::builder()
+                                .status(StatusCode::ACCEPTED)
+                                .header(hyper::header::LOCATION, format!("{}{}", crate::jobs::JOBS_PATH, id))
+                                .header(hyper::header::CONTENT_TYPE, "application/json")
+                                .body(full(body)).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::Timeout(body) => {
+                            let a = Response::builder().status(StatusCode::GATEWAY_TIMEOUT).body(full(Bytes::from(body))).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::BadGateway(body) => {
+                            let a = Response::builder()
+                                .status(StatusCode::BAD_GATEWAY)
+                                .header(hyper::header::CONTENT_TYPE, "application/json")
+                                .body(full(Bytes::from(body))).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::Unavailable { retry_after, body } => {
+                            let a = Response::builder()
+                                .status(StatusCode::SERVICE_UNAVAILABLE)
+                                .header(hyper::header::RETRY_AFTER, retry_after)
+                                .header(hyper::header::CONTENT_TYPE, "application/json")
+                                .body(full(Bytes::from(body))).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::NotFound => {
+                            let response = Bytes::from("Not Found");
+                            let a = Response::builder().status(StatusCode::NOT_FOUND).body(full(response)).unwrap();
+                            Ok(a)
+                        }
+                        FrontResponse::MethodNotAllowed(allowed) => {
+                            let allow = allowed.iter().map(|m| m.as_str()).collect::<Vec<&str>>().join(", ");
+                            let response = Bytes::from("Method Not Allowed");
+                            let a = Response::builder()
+                                .status(StatusCode::METHOD_NOT_ALLOWED)
+                                .header(hyper::header::ALLOW, allow)
+                                .body(full(response)).unwrap();
+                            Ok(a)
+                        }
                         FrontResponse::InternalError => {
                             let status = StatusCode::from_u16(500).unwrap();
                             let response = Bytes::from("Internal Error");
-                            let a = Response::builder().status(status).body(Full::new(response)).unwrap();
+                            let a = Response::builder().status(status).body(full(response)).unwrap();
                             //hresp = Response::builder().body(a)
                             Ok(a)
                         }
@@ -144,7 +251,7 @@ impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
                 }
                 Err(_e) => {
                     // this is the arbiter channel error, it should panic as well
-                    let b = Response::builder().status(500).body(Full::new(Bytes::from(""))).unwrap();
+                    let b = Response::builder().status(500).body(full(Bytes::from(""))).unwrap();
                     Ok(b)
                 }
             }
@@ -153,6 +260,34 @@ impl Service<Request<IncomingBody>> for Svc<RequestsVisor> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::toktor_new;
+    use crate::processcontroller::ProcessController;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    async fn http_request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf).to_string()
+    }
+
+    #[tokio::test]
+    async fn front_ephemeral_port() {
+        let conf = ServiceConf { bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], ..Default::default() };
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let addrs = run_front(&conf, &visor).await.unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_ne!(addrs[0].port(), 0);
+        let response = http_request(addrs[0], "GET /nowhere HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+}
+
 // use https://docs.rs/axum/latest/axum/routing/struct.Router.html#method.nest_service
 // and tower service:
 // https://docs.rs/tower-service/0.3.2/tower_service/trait.Service.html
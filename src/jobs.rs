@@ -0,0 +1,189 @@
+//! The store of the asynchronous requests (`async: true`): the client gets
+//! `202 Accepted` at once, then polls `GET /jobs/{id}` for the result.
+//! A job is pending until the reply (or the failure) comes, then it is kept
+//! for the `jobttl` and removed.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::Engine;
+use bytes::Bytes;
+use serde_json::{json, Value};
+use tokio::sync::Mutex as TMutex;
+use tokio::time::Duration;
+
+use crate::processcontroller::ProcessExit;
+use crate::requestsvisor::{ForHttpResponse, FrontResponse};
+
+/// default time the job results are kept, in ms
+pub const DEFAULT_JOB_TTL: u64 = 600000;
+/// the path prefix of the job status
+pub const JOBS_PATH: &str = "/jobs/";
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum JobState {
+    Pending,
+    /// the reply of the process
+    Done { code: u16, headers: Vec<(String,String)>, body: Bytes },
+    /// the process did not reply: deadline, exit without reply, invalid reply...
+    Failed { code: u16, error: Value },
+}
+
+#[derive(Debug,Clone)]
+struct Job {
+    state: JobState,
+    exit: Option<ProcessExit>,
+    process: Option<Value>,
+}
+
+#[derive(Clone,Default)]
+pub struct JobStore {
+    jobs: Arc<TMutex<HashMap<String, Job>>>,
+    ttl: u64,
+}
+
+impl JobStore {
+    pub fn new(ttl: u64) -> Self {
+        JobStore { jobs: Arc::new(TMutex::new(HashMap::new())), ttl }
+    }
+
+    pub async fn insert_pending(&self, id: &str) {
+        let job = Job { state: JobState::Pending, exit: None, process: None };
+        self.jobs.lock().await.insert(id.to_string(), job);
+    }
+
+    /// Store the outcome of the job, it is removed after the ttl
+    pub async fn finish(&self, id: &str, response: FrontResponse) {
+        let state = JobState::from(response);
+        match self.jobs.lock().await.get_mut(id) {
+            Some(job) => job.state = state,
+            None => return
+        }
+        let jobs = self.jobs.clone();
+        let id = id.to_string();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(ttl)).await;
+            jobs.lock().await.remove(&id);
+        });
+    }
+
+    /// Store the process exit status and its infos (`ProcessInfos::to_json`)
+    pub async fn set_exit(&self, id: &str, exit: ProcessExit, process: Option<Value>) {
+        if let Some(job) = self.jobs.lock().await.get_mut(id) {
+            job.exit = Some(exit);
+            job.process = process;
+        }
+    }
+
+    pub async fn state(&self, id: &str) -> Option<JobState> {
+        self.jobs.lock().await.get(id).map(|job| job.state.clone())
+    }
+
+    /// The job status document, None if the job is unknown or expired
+    pub async fn status(&self, id: &str) -> Option<String> {
+        let jobs = self.jobs.lock().await;
+        let job = jobs.get(id)?;
+        let mut status = match &job.state {
+            JobState::Pending => json!({"id": id, "status": "pending"}),
+            JobState::Done { code, headers, body } => {
+                let mut status = json!({"id": id, "status": "done", "code": code, "headers": headers});
+                match std::str::from_utf8(body) {
+                    Ok(text) => status["body"] = Value::String(text.to_string()),
+                    Err(_) => {
+                        status["body"] = Value::String(base64::engine::general_purpose::STANDARD.encode(body));
+                        status["bodyencoding"] = Value::String(String::from("base64"));
+                    }
+                }
+                status
+            }
+            JobState::Failed { code, error } => json!({"id": id, "status": "failed", "code": code, "error": error}),
+        };
+        if let Some(exit) = &job.exit {
+            status["exit"] = json!({"code": exit.code, "signal": exit.signal, "was_killed": exit.was_killed, "error": exit.error});
+        }
+        if let Some(process) = &job.process {
+            status["process"] = process.clone();
+        }
+        Some(status.to_string())
+    }
+}
+
+impl JobState {
+    /// The reply of an ended job: the failures have their error as JSON body
+    pub fn reply(&self) -> Option<ForHttpResponse> {
+        match self {
+            JobState::Pending => None,
+            JobState::Done { code, headers, body } => Some(ForHttpResponse {
+                code: *code as u32,
+                headers: headers.clone(),
+                body: body.clone(),
+            }),
+            JobState::Failed { code, error } => Some(ForHttpResponse {
+                code: *code as u32,
+                headers: vec![(String::from("content-type"), String::from("application/json"))],
+                body: Bytes::from(json!({"error": error}).to_string()),
+            }),
+        }
+    }
+}
+
+impl From<FrontResponse> for JobState {
+    fn from(response: FrontResponse) -> Self {
+        match response {
+            FrontResponse::BackMsg(r) => JobState::Done {
+                code: u16::try_from(r.code).unwrap_or(500),
+                headers: r.headers,
+                body: r.body
+            },
+            FrontResponse::Output { code, content_type, headers, body } => JobState::Done {
+                code,
+                headers: [vec![(String::from("content-type"), content_type)], headers].concat(),
+                body
+            },
+            FrontResponse::Timeout(body) => JobState::Failed { code: 504, error: Value::String(body) },
+            FrontResponse::BadGateway(body) => JobState::Failed {
+                code: 502,
+                error: serde_json::from_str(&body).unwrap_or(Value::String(body))
+            },
+            FrontResponse::Unavailable { body, .. } => JobState::Failed {
+                code: 503,
+                error: serde_json::from_str(&body).unwrap_or(Value::String(body))
+            },
+            FrontResponse::Stream { .. } => JobState::Failed {
+                code: 500,
+                error: Value::String(String::from("streamed replies are not stored"))
+            },
+            _ => JobState::Failed { code: 500, error: Value::String(String::from("internal error")) },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn job_lifecycle() {
+        let store = JobStore::new(100);
+        store.insert_pending("j1").await;
+        assert_eq!(store.state("j1").await, Some(JobState::Pending));
+        assert!(store.status("j1").await.unwrap().contains("\"pending\""));
+        let response = ForHttpResponse { code: 201, body: Bytes::from_static(&[0xff, 0x00]), ..Default::default() };
+        store.finish("j1", FrontResponse::BackMsg(response)).await;
+        store.set_exit("j1", ProcessExit { code: Some(0), ..Default::default() }, None).await;
+        let status: Value = serde_json::from_str(&store.status("j1").await.unwrap()).unwrap();
+        assert_eq!(status["status"], "done");
+        assert_eq!(status["code"], 201);
+        assert_eq!(status["body"], "/wA=");
+        assert_eq!(status["bodyencoding"], "base64");
+        assert_eq!(status["exit"]["code"], 0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(store.status("j1").await, None);
+        store.insert_pending("j2").await;
+        store.finish("j2", FrontResponse::Timeout(String::from("too slow"))).await;
+        assert_eq!(store.state("j2").await, Some(JobState::Failed { code: 504, error: Value::String(String::from("too slow")) }));
+        let reply = store.state("j2").await.unwrap().reply().unwrap();
+        assert_eq!((reply.code, reply.body), (504, Bytes::from("{\"error\":\"too slow\"}")));
+    }
+}
@@ -7,6 +7,14 @@ pub mod requestsvisor;
 pub mod restmessage;
 pub mod processcontroller;
 pub mod procenv;
+pub mod openapi;
+pub mod jobs;
+pub mod webhook;
+pub mod cgroup;
+pub mod admission;
+pub mod workerpool;
+pub mod capture;
+pub mod pidfd;
 
 pub use toktor::toktor_send;
 
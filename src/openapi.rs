@@ -0,0 +1,608 @@
+//! OpenApi - the OpenAPI 3.x document referenced by `openapi` in the service
+//! configuration, loaded with all the local `$ref` resolved.
+//! It is used to validate the incoming request (`validatein`) and the reply
+//! posted by the process (`validateout`) against the operation
+//! defined by `paths.[path].[verb]`.
+//!
+//! The schema validation covers the JSON Schema keywords used by OpenAPI:
+//! type (and `nullable`), enum, const, properties, required, additionalProperties,
+//! items, min/maxItems, uniqueItems, min/maxLength, minimum, maximum,
+//! exclusiveMinimum/Maximum, multipleOf, allOf, anyOf, oneOf, not.
+//! `pattern` and string `format`s are not checked.
+//! Only JSON request bodies are validated against the schema, for other media types
+//! just the content type is checked.
+
+use hyper::Method;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use tracing::warn;
+
+use crate::restmessage::RestMessage;
+
+pub struct OpenApi {
+    doc: Value,
+}
+
+impl std::fmt::Debug for OpenApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpenApi({} {} {})", self.doc["openapi"], self.doc["info"]["title"], self.doc["info"]["version"])
+    }
+}
+
+/// A single validation error
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// where: `body`, `path`, `query`, `header` or `content-type`
+    #[serde(rename = "in")]
+    pub location: String,
+    /// the parameter name, or the JSON pointer inside the body
+    pub name: String,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(location: &str, name: &str, message: &str) -> Self {
+        Violation { location: location.to_string(), name: name.to_string(), message: message.to_string() }
+    }
+}
+
+/// The validation errors and the http status to answer with (400 or 415)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violations {
+    pub code: u16,
+    pub errors: Vec<Violation>,
+}
+
+impl Violations {
+    /// The machine readable error list, as response body
+    pub fn to_json(&self) -> String {
+        serde_json::json!({"errors": self.errors}).to_string()
+    }
+}
+
+impl OpenApi {
+    /// Read and parse the OpenAPI document (YAML or JSON)
+    pub async fn load(filename: &str) -> Result<OpenApi, String> {
+        let mut f = File::open(filename).await
+            .map_err(|e| format!("can not open OpenAPI file \"{}\": {}", filename, e))?;
+        let mut content = String::new();
+        f.read_to_string(&mut content).await
+            .map_err(|e| format!("can not read OpenAPI file \"{}\": {}", filename, e))?;
+        let doc: Value = serde_yaml::from_str(&content)
+            .map_err(|e| format!("can not parse OpenAPI file \"{}\": {}", filename, e))?;
+        OpenApi::from_value(doc)
+    }
+
+    /// Check the version and resolve the `$ref`s of the document
+    pub fn from_value(doc: Value) -> Result<OpenApi, String> {
+        match doc["openapi"].as_str() {
+            Some(v) if v.starts_with("3.") => {},
+            _ => return Err(format!("unsupported OpenAPI version {}, expected 3.x", doc["openapi"]))
+        }
+        let doc = resolve_refs(&doc, &doc, &mut Vec::new())?;
+        Ok(OpenApi { doc })
+    }
+
+    /// The operation object `paths.[route].[method]`
+    pub fn operation(&self, route: &str, method: &Method) -> Option<&Value> {
+        let op = &self.doc["paths"][route][method.as_str().to_lowercase()];
+        if op.is_object() {
+            Some(op)
+        } else {
+            None
+        }
+    }
+
+    /// The parameters of the operation, path level parameters are
+    /// overridden by operation level ones with the same name and location
+    fn parameters<'a>(&'a self, route: &str, op: &'a Value) -> Vec<&'a Value> {
+        let mut params: Vec<&Value> = Vec::new();
+        let levels = [&self.doc["paths"][route]["parameters"], &op["parameters"]];
+        for p in levels.iter().filter_map(|l| l.as_array()).flatten() {
+            params.retain(|x| x["name"] != p["name"] || x["in"] != p["in"]);
+            params.push(p);
+        }
+        params
+    }
+
+    /// Validate path and query parameters, headers, content type and
+    /// body of the request against the operation matching its route
+    pub fn validate_request(&self, rm: &RestMessage) -> Result<(), Violations> {
+        let op = match self.operation(rm.route(), rm.method()) {
+            Some(op) => op,
+            None => {
+                warn!("no OpenAPI operation for {} {}, request not validated", rm.method(), rm.route());
+                return Ok(());
+            }
+        };
+        let mut errors = Vec::new();
+        let query = rm.query_params();
+        for param in self.parameters(rm.route(), op) {
+            check_parameter(param, rm, &query, &mut errors);
+        }
+        if let Some(request_body) = op.get("requestBody") {
+            if let Err(e) = check_body(request_body, rm, &mut errors) {
+                // the parameter violations are reported too
+                errors.push(e);
+                return Err(Violations { code: 415, errors });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Violations { code: 400, errors })
+        }
+    }
+}
+
+impl OpenApi {
+    /// Validate the JSON reply of the process against the `responses` of
+    /// the operation for the status `code` (exact, `2XX` alike or `default`)
+    pub fn validate_response(&self, route: &str, method: &Method, code: u16, content_type: Option<&str>, body: &[u8]) -> Result<(), Violations> {
+        let op = match self.operation(route, method) {
+            Some(op) => op,
+            None => {
+                warn!("no OpenAPI operation for {} {}, response not validated", method, route);
+                return Ok(());
+            }
+        };
+        let responses = &op["responses"];
+        let status = code.to_string();
+        let range = format!("{}XX", code / 100);
+        let response = [status.as_str(), range.as_str(), &range.to_lowercase(), "default"].iter()
+            .map(|k| &responses[*k])
+            .find(|r| r.is_object());
+        let fail = |name: &str, message: &str| Err(Violations { code: 502, errors: vec![Violation::new("response", name, message)] });
+        let response = match response {
+            Some(r) => r,
+            None => return fail(&status, "status code not documented")
+        };
+        let content = match response["content"].as_object() {
+            Some(c) if !c.is_empty() => c,
+            _ if body.is_empty() => return Ok(()),
+            _ => return fail(&status, "no content documented for the status code")
+        };
+        let media_type = content_type.unwrap_or("application/json").split(';').next().unwrap_or("").trim().to_lowercase();
+        let media = match match_media_type(content, &media_type) {
+            Some(m) => m,
+            None => return fail(&media_type, "media type not documented for the status code")
+        };
+        if !is_json(&media_type) {
+            return Ok(());
+        }
+        let body = match serde_json::from_slice::<Value>(body) {
+            Ok(b) => b,
+            Err(e) => return fail("", &format!("invalid JSON: {}", e))
+        };
+        let mut found = Vec::new();
+        validate_schema(&media["schema"], &body, "", &mut found);
+        if found.is_empty() {
+            Ok(())
+        } else {
+            Err(Violations {
+                code: 502,
+                errors: found.iter().map(|(pointer, message)| Violation::new("response", pointer, message)).collect()
+            })
+        }
+    }
+}
+
+fn resolve_refs(root: &Value, value: &Value, stack: &mut Vec<String>) -> Result<Value, String> {
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::String(r)) = map.get("$ref") {
+                if stack.contains(r) {
+                    // recursive schema: below this level anything is accepted
+                    return Ok(Value::Object(Map::new()));
+                }
+                let target = r.strip_prefix('#')
+                    .and_then(|pointer| root.pointer(pointer))
+                    .ok_or(format!("can not resolve $ref {}, only local references are supported", r))?;
+                stack.push(r.clone());
+                let resolved = resolve_refs(root, target, stack);
+                stack.pop();
+                return resolved;
+            }
+            let mut resolved = Map::new();
+            for (k, v) in map.iter() {
+                resolved.insert(k.clone(), resolve_refs(root, v, stack)?);
+            }
+            Ok(Value::Object(resolved))
+        }
+        Value::Array(a) => {
+            a.iter().map(|v| resolve_refs(root, v, stack)).collect::<Result<Vec<Value>,String>>().map(Value::Array)
+        }
+        _ => Ok(value.clone())
+    }
+}
+
+fn check_parameter(param: &Value, rm: &RestMessage, query: &[(String,String)], errors: &mut Vec<Violation>) {
+    let name = param["name"].as_str().unwrap_or("");
+    let location = param["in"].as_str().unwrap_or("");
+    let schema = &param["schema"];
+    let is_array = schema_types(schema).contains(&"array");
+    let raw: Vec<String> = match location {
+        "path" => rm.path_params().get(name).cloned().into_iter().collect(),
+        "query" => {
+            let values: Vec<String> = query.iter().filter(|(k, _)| k == name).map(|(_, v)| v.clone()).collect();
+            // form style explodes arrays by default: ?a=1&a=2, otherwise ?a=1,2
+            if is_array && param["explode"] == Value::Bool(false) {
+                values.iter().flat_map(|v| v.split(',').map(String::from)).collect()
+            } else {
+                values
+            }
+        }
+        "header" => rm.header(name).into_iter().collect(),
+        _ => return
+    };
+    if raw.is_empty() {
+        if param["required"] == Value::Bool(true) {
+            errors.push(Violation::new(location, name, "is required"));
+        }
+        return;
+    }
+    let value = if is_array {
+        let items: Vec<String> = if raw.len() == 1 && location != "query" {
+            raw[0].split(',').map(String::from).collect()
+        } else {
+            raw
+        };
+        Value::Array(items.iter().map(|x| coerce(&schema["items"], x)).collect())
+    } else {
+        coerce(schema, &raw[0])
+    };
+    let mut found = Vec::new();
+    validate_schema(schema, &value, "", &mut found);
+    for (pointer, message) in found {
+        errors.push(Violation::new(location, &format!("{}{}", name, pointer), &message));
+    }
+}
+
+/// Check the request body: returns Err if the content type is not accepted
+fn check_body(request_body: &Value, rm: &RestMessage, errors: &mut Vec<Violation>) -> Result<(), Violation> {
+    if rm.body().is_empty() {
+        if request_body["required"] == Value::Bool(true) {
+            errors.push(Violation::new("body", "", "request body is required"));
+        }
+        return Ok(());
+    }
+    let content_type = rm.header("content-type").unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let content = match request_body["content"].as_object() {
+        Some(c) => c,
+        None => return Ok(())
+    };
+    let media = match match_media_type(content, &media_type) {
+        Some(m) => m,
+        None => {
+            let accepted: Vec<&str> = content.keys().map(|k| k.as_str()).collect();
+            let message = format!("unsupported content type \"{}\", expected {}", media_type, accepted.join(" or "));
+            return Err(Violation::new("content-type", &media_type, &message));
+        }
+    };
+    if is_json(&media_type) {
+        match serde_json::from_slice::<Value>(rm.body()) {
+            Ok(body) => {
+                let mut found = Vec::new();
+                validate_schema(&media["schema"], &body, "", &mut found);
+                for (pointer, message) in found {
+                    errors.push(Violation::new("body", &pointer, &message));
+                }
+            }
+            Err(e) => errors.push(Violation::new("body", "", &format!("invalid JSON: {}", e)))
+        }
+    }
+    Ok(())
+}
+
+/// The media type object for `media_type`: an exact match, or `type/*`, or `*/*`
+pub fn match_media_type<'a>(content: &'a Map<String, Value>, media_type: &str) -> Option<&'a Value> {
+    let main_type = media_type.split('/').next().unwrap_or("");
+    content.get(media_type)
+        .or_else(|| content.get(&format!("{}/*", main_type)))
+        .or_else(|| content.get("*/*"))
+}
+
+pub fn is_json(media_type: &str) -> bool {
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// Convert a path, query or header parameter to the type of its schema
+fn coerce(schema: &Value, raw: &str) -> Value {
+    let types = schema_types(schema);
+    if types.contains(&"integer") {
+        if let Ok(i) = raw.parse::<i64>() {
+            return Value::from(i);
+        }
+    }
+    if types.contains(&"number") {
+        if let Some(n) = raw.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+            return Value::Number(n);
+        }
+    }
+    if types.contains(&"boolean") && (raw == "true" || raw == "false") {
+        return Value::Bool(raw == "true");
+    }
+    Value::String(raw.to_string())
+}
+
+/// The `type` of the schema, OpenAPI 3.1 allows a list of types
+fn schema_types(schema: &Value) -> Vec<&str> {
+    match &schema["type"] {
+        Value::String(t) => vec![t.as_str()],
+        Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+        _ => vec![]
+    }
+}
+
+fn type_matches(t: &str, value: &Value) -> bool {
+    match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().map(|f| f.fract() == 0.0).unwrap_or(false),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true
+    }
+}
+
+fn escape_pointer(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn is_valid(schema: &Value, value: &Value) -> bool {
+    let mut found = Vec::new();
+    validate_schema(schema, value, "", &mut found);
+    found.is_empty()
+}
+
+/// Validate `value` against `schema`, the errors are pushed as
+/// (JSON pointer of the invalid value, message)
+pub fn validate_schema(schema: &Value, value: &Value, pointer: &str, errors: &mut Vec<(String,String)>) {
+    let s = match schema.as_object() {
+        Some(s) => s,
+        None => return
+    };
+    if value.is_null() && s.get("nullable") == Some(&Value::Bool(true)) {
+        return;
+    }
+    let types = schema_types(schema);
+    if !types.is_empty() && !types.iter().any(|t| type_matches(t, value)) {
+        errors.push((pointer.to_string(), format!("expected {}", types.join(" or "))));
+        return;
+    }
+    if let Some(Value::Array(allowed)) = s.get("enum") {
+        if !allowed.contains(value) {
+            errors.push((pointer.to_string(), format!("must be one of {}", Value::Array(allowed.clone()))));
+        }
+    }
+    if let Some(c) = s.get("const") {
+        if c != value {
+            errors.push((pointer.to_string(), format!("must be {}", c)));
+        }
+    }
+    match value {
+        Value::String(st) => {
+            let len = st.chars().count() as u64;
+            if let Some(min) = s.get("minLength").and_then(|v| v.as_u64()) {
+                if len < min {
+                    errors.push((pointer.to_string(), format!("shorter than {} characters", min)));
+                }
+            }
+            if let Some(max) = s.get("maxLength").and_then(|v| v.as_u64()) {
+                if len > max {
+                    errors.push((pointer.to_string(), format!("longer than {} characters", max)));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let x = n.as_f64().unwrap_or(0.0);
+            let exclusive = |k: &str| s.get(k) == Some(&Value::Bool(true));
+            if let Some(min) = s.get("minimum").and_then(|v| v.as_f64()) {
+                if x < min || (exclusive("exclusiveMinimum") && x == min) {
+                    errors.push((pointer.to_string(), format!("less than the minimum {}", min)));
+                }
+            }
+            if let Some(max) = s.get("maximum").and_then(|v| v.as_f64()) {
+                if x > max || (exclusive("exclusiveMaximum") && x == max) {
+                    errors.push((pointer.to_string(), format!("greater than the maximum {}", max)));
+                }
+            }
+            // OpenAPI 3.1 numeric exclusive bounds
+            if let Some(min) = s.get("exclusiveMinimum").and_then(|v| v.as_f64()) {
+                if x <= min {
+                    errors.push((pointer.to_string(), format!("not greater than {}", min)));
+                }
+            }
+            if let Some(max) = s.get("exclusiveMaximum").and_then(|v| v.as_f64()) {
+                if x >= max {
+                    errors.push((pointer.to_string(), format!("not less than {}", max)));
+                }
+            }
+            if let Some(m) = s.get("multipleOf").and_then(|v| v.as_f64()) {
+                if m > 0.0 && (x / m).fract() != 0.0 {
+                    errors.push((pointer.to_string(), format!("not a multiple of {}", m)));
+                }
+            }
+            match s.get("format").and_then(|v| v.as_str()) {
+                Some("int32") if n.as_i64().map(|i| i32::try_from(i).is_err()).unwrap_or(true) => {
+                    errors.push((pointer.to_string(), String::from("out of int32 range")));
+                }
+                Some("int64") if n.as_i64().is_none() => {
+                    errors.push((pointer.to_string(), String::from("out of int64 range")));
+                }
+                _ => {}
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = s.get("minItems").and_then(|v| v.as_u64()) {
+                if len < min {
+                    errors.push((pointer.to_string(), format!("less than {} items", min)));
+                }
+            }
+            if let Some(max) = s.get("maxItems").and_then(|v| v.as_u64()) {
+                if len > max {
+                    errors.push((pointer.to_string(), format!("more than {} items", max)));
+                }
+            }
+            if s.get("uniqueItems") == Some(&Value::Bool(true)) {
+                for (i, item) in items.iter().enumerate() {
+                    if items[..i].contains(item) {
+                        errors.push((format!("{}/{}", pointer, i), String::from("duplicated item")));
+                    }
+                }
+            }
+            if let Some(item_schema) = s.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_schema(item_schema, item, &format!("{}/{}", pointer, i), errors);
+                }
+            }
+        }
+        Value::Object(map) => {
+            if let Some(Value::Array(required)) = s.get("required") {
+                for name in required.iter().filter_map(|r| r.as_str()) {
+                    if !map.contains_key(name) {
+                        errors.push((format!("{}/{}", pointer, escape_pointer(name)), String::from("is required")));
+                    }
+                }
+            }
+            let properties = s.get("properties").and_then(|p| p.as_object());
+            for (name, v) in map.iter() {
+                let child = format!("{}/{}", pointer, escape_pointer(name));
+                match properties.and_then(|p| p.get(name)) {
+                    Some(prop_schema) => validate_schema(prop_schema, v, &child, errors),
+                    None => match s.get("additionalProperties") {
+                        Some(Value::Bool(false)) => errors.push((child, String::from("unexpected property"))),
+                        Some(additional) => validate_schema(additional, v, &child, errors),
+                        None => {}
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    if let Some(Value::Array(all)) = s.get("allOf") {
+        for sub in all {
+            validate_schema(sub, value, pointer, errors);
+        }
+    }
+    if let Some(Value::Array(any)) = s.get("anyOf") {
+        if !any.iter().any(|sub| is_valid(sub, value)) {
+            errors.push((pointer.to_string(), String::from("does not match any of anyOf")));
+        }
+    }
+    if let Some(Value::Array(one)) = s.get("oneOf") {
+        let matching = one.iter().filter(|sub| is_valid(sub, value)).count();
+        if matching != 1 {
+            errors.push((pointer.to_string(), format!("matches {} of oneOf, expected exactly one", matching)));
+        }
+    }
+    if let Some(not) = s.get("not") {
+        if is_valid(not, value) {
+            errors.push((pointer.to_string(), String::from("must not match the `not` schema")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    async fn petstore() -> OpenApi {
+        OpenApi::load("examples/pets-oas.yaml").await.unwrap()
+    }
+
+    fn request(method: &str, route: &str, uri: &str, content_type: &str, body: &str) -> RestMessage {
+        let mut rm = RestMessage::new(method, uri, body);
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let mut params = HashMap::new();
+        for (t, u) in route.split('/').zip(path.split('/')) {
+            if t.starts_with('{') {
+                params.insert(t.trim_matches(|c| c == '{' || c == '}').to_string(), u.to_string());
+            }
+        }
+        rm.set_route(route, params);
+        rm.set_query(query);
+        if !content_type.is_empty() {
+            rm.headers_mut().insert("content-type", content_type.parse().unwrap());
+        }
+        rm
+    }
+
+    #[tokio::test]
+    async fn validate_request_body() {
+        let api = petstore().await;
+        let ok = request("post", "/pet", "/pet", "application/json", r#"{"name": "doggie", "photoUrls": [], "status": "sold"}"#);
+        assert_eq!(api.validate_request(&ok), Ok(()));
+        let bad = request("post", "/pet", "/pet", "application/json; charset=utf-8", r#"{"photoUrls": [1], "status": "lost", "category": {"id": "x"}}"#);
+        let violations = api.validate_request(&bad).unwrap_err();
+        assert_eq!(violations.code, 400);
+        let names: Vec<&str> = violations.errors.iter().map(|v| v.name.as_str()).collect();
+        assert!(names.contains(&"/name"));
+        assert!(names.contains(&"/photoUrls/0"));
+        assert!(names.contains(&"/status"));
+        assert!(names.contains(&"/category/id"));
+        let missing = request("post", "/pet", "/pet", "application/json", "");
+        assert_eq!(api.validate_request(&missing).unwrap_err().code, 400);
+        let text = request("post", "/pet", "/pet", "text/plain", "doggie");
+        let violations = api.validate_request(&text).unwrap_err();
+        assert_eq!(violations.code, 415);
+        assert_eq!(violations.errors[0].location, "content-type");
+        // the parameter violations are kept with the 415
+        let both = request("post", "/pet/{petId}/uploadImage", "/pet/doggie/uploadImage", "text/plain", "doggie");
+        let violations = api.validate_request(&both).unwrap_err();
+        assert_eq!(violations.code, 415);
+        let locations: Vec<&str> = violations.errors.iter().map(|v| v.location.as_str()).collect();
+        assert_eq!(locations, vec!["path", "content-type"]);
+        let xml = request("post", "/pet", "/pet", "application/xml", "<Pet/>");
+        assert_eq!(api.validate_request(&xml), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn validate_request_parameters() {
+        let api = petstore().await;
+        let ok = request("get", "/pet/{petId}", "/pet/12", "", "");
+        assert_eq!(api.validate_request(&ok), Ok(()));
+        let bad = request("get", "/pet/{petId}", "/pet/doggie", "", "");
+        let violations = api.validate_request(&bad).unwrap_err();
+        assert_eq!(violations.errors, vec![Violation::new("path", "petId", "expected integer")]);
+        let ok = request("get", "/pet/findByStatus", "/pet/findByStatus?status=sold&status=pending", "", "");
+        assert_eq!(api.validate_request(&ok), Ok(()));
+        let bad = request("get", "/pet/findByStatus", "/pet/findByStatus?status=lost", "", "");
+        assert_eq!(api.validate_request(&bad).unwrap_err().errors[0].name, "status/0");
+        let missing = request("get", "/pet/findByStatus", "/pet/findByStatus", "", "");
+        let violations = api.validate_request(&missing).unwrap_err();
+        assert_eq!(violations.to_json(), r#"{"errors":[{"in":"query","message":"is required","name":"status"}]}"#);
+    }
+
+    #[tokio::test]
+    async fn validate_response() {
+        let api = petstore().await;
+        let pet = br#"{"id": 12, "name": "doggie", "photoUrls": ["a.png"]}"#;
+        assert_eq!(api.validate_response("/pet/{petId}", &Method::GET, 200, None, pet), Ok(()));
+        let bad = br#"{"id": "twelve", "photoUrls": []}"#;
+        let violations = api.validate_response("/pet/{petId}", &Method::GET, 200, Some("application/json"), bad).unwrap_err();
+        assert_eq!(violations.code, 502);
+        assert_eq!(violations.errors.len(), 2);
+        assert_eq!(api.validate_response("/pet/{petId}", &Method::GET, 404, None, b""), Ok(()));
+        assert!(api.validate_response("/pet/{petId}", &Method::GET, 404, None, pet).is_err());
+        assert!(api.validate_response("/pet/{petId}", &Method::GET, 200, Some("text/csv"), b"id\n12").is_err());
+        let violations = api.validate_response("/pet/{petId}", &Method::GET, 418, None, pet).unwrap_err();
+        assert_eq!(violations.errors[0].message, "status code not documented");
+    }
+
+    #[test]
+    fn unresolved_ref() {
+        let doc = serde_json::json!({"openapi": "3.0.1", "paths": {"/a": {"get": {"$ref": "other.yaml#/x"}}}});
+        assert!(OpenApi::from_value(doc).is_err());
+        let doc = serde_json::json!({"swagger": "2.0"});
+        assert!(OpenApi::from_value(doc).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Child processes tracked by Linux pidfds (`pidfd_open`, Linux >= 5.3):
+//! the pidfd is registered in the tokio reactor and becomes readable when
+//! the process exits, no thread waits for it. A process that left its
+//! group is signalled by `pidfd_send_signal`. Without pidfd support the
+//! exit is awaited by a blocking `waitid(WNOWAIT)` task.
+//!
+//! The process is not reaped here: the caller `wait4`s it once exited, to
+//! collect its ResUse. Until then its pid, that is also its process group
+//! id, can not be recycled, so signalling the group is safe as well.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use tokio::io::unix::AsyncFd;
+use tracing::{trace, warn};
+
+pub struct PidFd {
+    pid: u32,
+    fd: Option<AsyncFd<OwnedFd>>,
+}
+
+impl PidFd {
+    pub fn open(pid: u32) -> Self {
+        let raw = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+        let fd = if raw < 0 {
+            warn!("Pid({pid}) pidfd_open error {}", std::io::Error::last_os_error());
+            None
+        } else {
+            let fd = unsafe { OwnedFd::from_raw_fd(raw as i32) };
+            match AsyncFd::with_interest(fd, tokio::io::Interest::READABLE) {
+                Ok(fd) => Some(fd),
+                Err(e) => {
+                    warn!("Pid({pid}) pidfd registration error {}", e);
+                    None
+                }
+            }
+        };
+        PidFd { pid, fd }
+    }
+
+    /// Resolves when the process exits, it is left to be reaped
+    pub async fn exited(&self) {
+        match &self.fd {
+            Some(fd) => {
+                let _ = fd.readable().await;
+            }
+            None => {
+                let pid = self.pid;
+                let _ = tokio::task::spawn_blocking(move || unsafe {
+                    let mut info: libc::siginfo_t = std::mem::zeroed();
+                    libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT)
+                }).await;
+            }
+        }
+    }
+
+    /// Send the signal to the process group, once to each member. The
+    /// process gets it by pidfd only if it left the group (`setsid`)
+    pub fn signal_group(&self, signal: i32) {
+        let pid = self.pid as libc::pid_t;
+        if unsafe { libc::kill(-pid, signal) } < 0 {
+            trace!("Pid({}) group signal {} error {}", self.pid, signal, std::io::Error::last_os_error());
+        }
+        if unsafe { libc::getpgid(pid) } == pid {
+            return;
+        }
+        let sent = match &self.fd {
+            Some(fd) => unsafe {
+                libc::syscall(libc::SYS_pidfd_send_signal, fd.as_raw_fd(), signal, std::ptr::null::<libc::siginfo_t>(), 0)
+            },
+            None => unsafe { libc::kill(pid, signal) as libc::c_long },
+        };
+        if sent < 0 {
+            warn!("Pid({}) signal {} error {}", self.pid, signal, std::io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[tokio::test]
+    async fn pidfd_exit_and_signal() {
+        let mut child = Command::new("sleep").arg("0.1").spawn().unwrap();
+        let pidfd = PidFd::open(child.id());
+        tokio::time::timeout(std::time::Duration::from_secs(2), pidfd.exited()).await.unwrap();
+        // exited but not reaped yet
+        assert!(child.try_wait().unwrap().unwrap().success());
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pidfd = PidFd::open(child.id());
+        // not a group leader: signalled by the pidfd
+        pidfd.signal_group(libc::SIGKILL);
+        tokio::time::timeout(std::time::Duration::from_secs(2), pidfd.exited()).await.unwrap();
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+}
@@ -51,14 +51,132 @@ impl<'a> CmdDefinition {
 }
 
 
+/// How the request payload reaches the process
+#[derive(Serialize,Deserialize,Debug,Clone,Default,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// in the command line (or env), by the `{{jsonpayload}}` placeholder
+    #[default]
+    Cmdline,
+    /// written on the process stdin, then stdin is closed
+    Stdin,
+}
+
+/// How the request body is handed to the process
+#[derive(Serialize,Deserialize,Debug,Clone,Default,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// as text (invalid UTF-8 is replaced), by the `channel`
+    #[default]
+    Json,
+    /// the body bytes as they are, always on stdin
+    Raw,
+    /// base64 encoded, in the `{{payload}}` placeholder (and on stdin with `channel: stdin`)
+    Base64,
+    /// written to a temporary file, removed when the process exits: the path is in the
+    /// `{{payload}}` placeholder and in the `PAYLOAD_FILE` env
+    File,
+}
+
+impl From<&str> for Encoding {
+    /// Unknown names fall back to `Json`
+    fn from(value: &str) -> Self {
+        match value {
+            "raw" => Encoding::Raw,
+            "base64" => Encoding::Base64,
+            "file" => Encoding::File,
+            _ => Encoding::Json,
+        }
+    }
+}
+
+/// A cgroup limit value, as a number or as written in the cgroup file (`512M`, `max`, `50000 100000`)
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+#[serde(untagged)]
+pub enum LimitValue {
+    Number(u64),
+    Text(String),
+}
+
+impl std::fmt::Display for LimitValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitValue::Number(n) => write!(f, "{}", n),
+            LimitValue::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+/// cgroups v2 resource limits of the process, the keys are the cgroup files
+#[derive(Serialize,Deserialize,Debug,Clone,Default,PartialEq)]
+pub struct Limits {
+    #[serde(rename = "memory.max")]
+    pub memory_max: Option<LimitValue>,
+    #[serde(rename = "cpu.max")]
+    pub cpu_max: Option<LimitValue>,
+    #[serde(rename = "pids.max")]
+    pub pids_max: Option<LimitValue>,
+    #[serde(rename = "io.weight")]
+    pub io_weight: Option<u16>,
+    /// the delegated cgroup subtree owned by urocket, default `/sys/fs/cgroup/urocket`
+    pub root: Option<String>,
+    /// a cgroup shared by the processes of the route, by default each request gets its own
+    pub group: Option<String>,
+}
+
+/// Pre-started workers serving one request per line (`pool` execution mode)
+#[derive(Serialize,Deserialize,Debug,Clone,Default,PartialEq)]
+pub struct PoolConf {
+    /// workers started up front
+    pub workers: usize,
+    /// requests served by a worker before it is recycled
+    pub maxrequests: Option<u64>,
+    /// resident memory (KB) over which a worker is recycled, checked after each request
+    pub maxmemory: Option<u64>,
+}
+
+/// Which variables of the urocket environment the process inherits
+#[derive(Serialize,Deserialize,Debug,Clone,Default,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvMode {
+    /// all of them
+    #[default]
+    Inherit,
+    /// none of them
+    Clean,
+    /// only the ones named in `envallow`
+    Allowlist,
+}
+
 #[derive(Serialize,Deserialize,Debug,Clone,Default)]
 pub struct ProcEnv {
     pub wd: String,
     pub env: Vec<String>,
     pub cmd: CmdDefinition,
     pub timeout: Option<u32>,
-    pub encoding: String,
-    pub channel: String
+    /// ms between the SIGTERM sent on timeout and the SIGKILL (default 2000)
+    pub killgrace: Option<u32>,
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub channel: Channel,
+    #[serde(default)]
+    pub limits: Option<Limits>,
+    /// requests go to long-lived workers instead of a new process each
+    #[serde(default)]
+    pub pool: Option<PoolConf>,
+    /// bytes kept of stdout, and of stderr (default 10MB), the rest is truncated
+    pub maxoutput: Option<usize>,
+    /// the output over `maxoutput` is written to a temporary file instead of dropped
+    #[serde(default)]
+    pub spilloutput: bool,
+    /// ms the spill files are kept (default 600000), then removed
+    pub spillttl: Option<u64>,
+    #[serde(default, alias = "env_mode")]
+    pub envmode: EnvMode,
+    /// the variables passed through with `envmode: allowlist`
+    #[serde(default, alias = "env_allow")]
+    pub envallow: Vec<String>,
 }
 
 impl ProcEnv {
@@ -67,9 +185,17 @@ impl ProcEnv {
             wd: wd.to_string(),
             env,
             cmd: CmdDefinition::from(cmd),
-            encoding: encoding.to_string(),
+            encoding: Encoding::from(encoding),
             timeout: Some(1000),
-            channel: "cmdline".to_string()
+            killgrace: None,
+            channel: Channel::Cmdline,
+            limits: None,
+            pool: None,
+            maxoutput: None,
+            spilloutput: false,
+            spillttl: None,
+            envmode: EnvMode::Inherit,
+            envallow: vec![],
         }
     }
     pub fn new_v(wd: &str, env: Vec<&str>, cmd: &[&str], encoding: &str) -> Self 
@@ -80,8 +206,16 @@ impl ProcEnv {
             //cmd: CmdDefinition::Splitted(cmd.iter().map(|x|{x.to_string()}).collect()),
             cmd: CmdDefinition::from(cmd.to_vec()),
             timeout: Some(1000),
-            encoding: encoding.to_string(),
-            channel: "cmdline".to_string()
+            killgrace: None,
+            encoding: Encoding::from(encoding),
+            channel: Channel::Cmdline,
+            limits: None,
+            pool: None,
+            maxoutput: None,
+            spilloutput: false,
+            spillttl: None,
+            envmode: EnvMode::Inherit,
+            envallow: vec![],
         }
     }
     
@@ -98,6 +232,61 @@ impl ProcEnv {
         }).collect()
     }
 
+    /// The variables passed on from the urocket environment, `None` when all of them are.
+    /// `PATH` is always passed: a command without path is looked up in it
+    pub fn inherited_env(&self) -> Option<Vec<(String, std::ffi::OsString)>> {
+        let names: Vec<&str> = match self.envmode {
+            EnvMode::Inherit => return None,
+            EnvMode::Clean => vec!["PATH"],
+            EnvMode::Allowlist => std::iter::once("PATH")
+                .chain(self.envallow.iter().map(String::as_str).filter(|name| *name != "PATH"))
+                .collect(),
+        };
+        Some(names.into_iter()
+            .filter_map(|name| std::env::var_os(name).map(|val| (name.to_string(), val)))
+            .collect())
+    }
+
+    /// Reject the fields a `pool` does not honour: its workers are started up front,
+    /// outside of any request, and get the requests as JSON lines
+    pub fn check_pool(&self) -> Result<(), String> {
+        if self.pool.is_none() {
+            return Ok(());
+        }
+        // no request to fill them: they would reach the workers as `{{...}}`
+        let cmd_placeholders = match &self.cmd {
+            CmdDefinition::ToSplit(cmd) => cmd.contains("{{"),
+            CmdDefinition::Splitted(args) => args.iter().any(|arg| arg.contains("{{")),
+        };
+        let unsupported: Vec<&str> = [
+            ("placeholders in cmd", cmd_placeholders),
+            ("placeholders in env", self.env.iter().any(|var| var.contains("{{"))),
+            ("limits", self.limits.is_some()),
+            ("encoding", self.encoding != Encoding::Json),
+            ("channel", self.channel != Channel::Cmdline),
+            ("maxoutput", self.maxoutput.is_some()),
+            ("spilloutput", self.spilloutput),
+        ].iter().filter(|(_, set)| *set).map(|(name, _)| *name).collect();
+        if unsupported.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("`pool` does not support {}", unsupported.join(", ")))
+        }
+    }
+
+    /// The working directory of the process, `None` keeps the urocket one
+    pub fn work_dir(&self) -> Result<Option<&std::path::Path>, String> {
+        if self.wd.is_empty() {
+            return Ok(None);
+        }
+        let wd = std::path::Path::new(&self.wd);
+        match std::fs::metadata(wd) {
+            Ok(meta) if meta.is_dir() => Ok(Some(wd)),
+            Ok(_) => Err(format!("working directory {} is not a directory", self.wd)),
+            Err(e) => Err(format!("working directory {} error: {}", self.wd, e)),
+        }
+    }
+
     pub fn get_env_replacements<'a>(&'a self, placeholders: &HashMap<&'a str,&'a str>) -> Vec<(&'a str, String)> {
         self.get_env().iter().map(|(name, val)| {
             let v = text_placeholder::Template::new(val).fill_with_hashmap(&placeholders);
@@ -145,4 +334,75 @@ mod tests {
         assert_eq!(v0.1,"123");
     }
 
+    #[test]
+    fn proc_env_channel() {
+        let yaml = "wd: /tmp\nenv: []\ncmd: !ToSplit cat\nencoding: json\n";
+        let penv: ProcEnv = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(penv.channel, Channel::Cmdline);
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}channel: stdin\n")).unwrap();
+        assert_eq!(penv.channel, Channel::Stdin);
+        assert!(serde_yaml::from_str::<ProcEnv>(&format!("{yaml}channel: pipe\n")).is_err());
+    }
+
+    #[test]
+    fn proc_env_limits() {
+        let yaml = "wd: /tmp\nenv: []\ncmd: !ToSplit cat\n";
+        let penv: ProcEnv = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(penv.limits, None);
+        let limits = "limits:\n  memory.max: 64M\n  cpu.max: 50000 100000\n  pids.max: 32\n  io.weight: 50\n";
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}{limits}")).unwrap();
+        let limits = penv.limits.unwrap();
+        assert_eq!(limits.memory_max.unwrap().to_string(), "64M");
+        assert_eq!(limits.cpu_max.unwrap().to_string(), "50000 100000");
+        assert_eq!(limits.pids_max, Some(LimitValue::Number(32)));
+        assert_eq!(limits.io_weight, Some(50));
+        assert_eq!(limits.group, None);
+    }
+
+    #[test]
+    fn proc_env_inheritance() {
+        let yaml = "wd: /tmp\nenv: []\ncmd: !ToSplit cat\n";
+        let penv: ProcEnv = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(penv.envmode, EnvMode::Inherit);
+        assert_eq!(penv.inherited_env(), None);
+        assert_eq!(penv.work_dir(), Ok(Some(std::path::Path::new("/tmp"))));
+        let path = ("PATH".to_string(), std::env::var_os("PATH").unwrap());
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}envmode: clean\n")).unwrap();
+        assert_eq!(penv.inherited_env(), Some(vec![path.clone()]));
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}env_mode: allowlist\nenvallow: [PATH, UROCKET_NOT_SET]\n")).unwrap();
+        assert_eq!(penv.inherited_env(), Some(vec![path.clone()]));
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}env_mode: allowlist\nenvallow: [HOME]\n")).unwrap();
+        let inherited = penv.inherited_env().unwrap();
+        assert_eq!(inherited[0], path);
+        assert_eq!(inherited[1].0, "HOME");
+        let penv = ProcEnv::new("/not/existent/dir", vec![], "cat", "");
+        assert!(penv.work_dir().unwrap_err().contains("/not/existent/dir"));
+        assert_eq!(ProcEnv::new("", vec![], "cat", "").work_dir(), Ok(None));
+    }
+
+    #[test]
+    fn proc_env_pool() {
+        let yaml = "wd: /tmp\nenv: []\ncmd: !ToSplit cat\npool:\n  workers: 2\n";
+        let penv: ProcEnv = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(penv.check_pool(), Ok(()));
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}channel: stdin\nlimits:\n  pids.max: 8\n")).unwrap();
+        assert_eq!(penv.check_pool(), Err(String::from("`pool` does not support limits, channel")));
+        let penv = ProcEnv::new("", vec![], "cat", "base64");
+        assert_eq!(penv.check_pool(), Ok(()));
+        let penv: ProcEnv = serde_yaml::from_str("wd: /tmp\nenv: []\ncmd: !ToSplit php worker.php {{path.id}}\npool:\n  workers: 2\n").unwrap();
+        assert_eq!(penv.check_pool(), Err(String::from("`pool` does not support placeholders in cmd")));
+        let penv: ProcEnv = serde_yaml::from_str("wd: /tmp\nenv: [\"TENANT={{header.x-tenant}}\"]\ncmd: !Splitted [php, worker.php]\npool:\n  workers: 2\n").unwrap();
+        assert_eq!(penv.check_pool(), Err(String::from("`pool` does not support placeholders in env")));
+    }
+
+    #[test]
+    fn proc_env_encoding() {
+        let yaml = "wd: /tmp\nenv: []\ncmd: !ToSplit cat\n";
+        let penv: ProcEnv = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(penv.encoding, Encoding::Json);
+        let penv: ProcEnv = serde_yaml::from_str(&format!("{yaml}encoding: base64\n")).unwrap();
+        assert_eq!(penv.encoding, Encoding::Base64);
+        assert!(serde_yaml::from_str::<ProcEnv>(&format!("{yaml}encoding: utf16\n")).is_err());
+    }
+
 }
\ No newline at end of file
@@ -1,13 +1,16 @@
 /// Process Controller - Controls OS process spawned, and stops them if timeout expires
-/// This just spawn process and after timeout send kill 9 (SIGKILL) and wait4 to get exit status
+/// This just spawn process and after timeout kills it, and wait4 to get exit status
 /// There are three cases:
 ///  1. timeout
 ///  2. normal termination
 ///  3. abnormal termination (exit code != 0)
-/// Timeout is controlled by `timeout` in ProcEnv, after timeout ms the process receive
-/// kill() with SIGKILL (9).
-/// Note: timeout's kill is called in a std::thread::spawn, not tokio async rt, this
-/// is more reliable.
+///
+/// Each process is started in its own process group. Timeout is controlled by `timeout`
+/// in ProcEnv: after timeout ms the group receives SIGTERM, then SIGKILL if the process
+/// is still there after `killgrace` ms, so `/bin/sh -c` wrappers do not leave
+/// grandchildren running.
+/// Note: the process is tracked by a pidfd in the tokio reactor (see `pidfd`), the
+/// timeout runs on tokio timers: no thread is tied up by a running process.
 /// ProcessInfos containing the execution infos with details (including stderr and stdout),
 /// can be requested by:
 /// 
@@ -28,10 +31,12 @@
 
 
 use std::sync::Arc;
-use tokio::sync::{Mutex as TMutex, mpsc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Mutex as TMutex, mpsc, oneshot, watch};
 use tokio::sync::mpsc::Sender;
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::process::{Command, Stdio};
 use wait4::{ResUse, Wait4};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -40,34 +45,71 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{trace, warn};
 
 
-use crate::{procenv::ProcEnv, restmessage::RestMessage};
+use crate::{procenv::{ProcEnv, Channel, Encoding}, restmessage::RestMessage};
+use crate::cgroup::{CGroup, CGroupStats};
+use crate::workerpool::{PoolJob, WorkerPool, request_line};
+use crate::capture::{capture, expire_spills, Captured, DEFAULT_MAX_OUTPUT, DEFAULT_SPILL_TTL};
+use crate::pidfd::PidFd;
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+use base64::Engine;
+use bytes::Bytes;
 extern crate toktor;
 use toktor::actor_handler;
 use crate::toktor_send;
 
-fn get_now_ms() -> u128 {
+/// default time between SIGTERM and SIGKILL, in ms
+const DEFAULT_KILL_GRACE: u32 = 2000;
+/// max wait for the output to end after SIGKILL, in ms: a descendant
+/// out of the process group (`setsid`) can keep the pipes open
+const OUTPUT_GRACE: u64 = 1000;
+
+pub(crate) fn get_now_ms() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH)
     .unwrap().as_millis()
 }
 
+/// Sleep until the kill time (ms since epoch), it can be pushed back meanwhile
+pub(crate) async fn kill_time(kill_at: &AtomicU64) {
+    loop {
+        let now = get_now_ms() as u64;
+        let at = kill_at.load(Ordering::SeqCst);
+        if now >= at {
+            return;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(at - now)).await;
+    }
+}
+
 enum ProcMsg {
     AddProc {
-        proce: ProcEnv,
-        rest_message: RestMessage,
-        uuid: String
+        proce: Box<ProcEnv>,
+        rest_message: Box<RestMessage>,
+        uuid: String,
+        exited: oneshot::Sender<ProcessExit>,
+        stdout_stream: Option<mpsc::UnboundedSender<Bytes>>,
     },
     GetInfos {
         uuid: String,
         tx: tokio::sync::mpsc::Sender<Option<ProcessInfos>>
     },
+    Extend {
+        uuid: String,
+        by_ms: u64
+    },
+    StartPool {
+        proce: Box<ProcEnv>
+    },
 }
 
 impl ProcMsg {
-    fn new_proc(proce: &ProcEnv, restmessage: RestMessage, uuid: &str) -> Self {
+    fn new_proc(proce: &ProcEnv, restmessage: RestMessage, uuid: &str, exited: oneshot::Sender<ProcessExit>, stdout_stream: Option<mpsc::UnboundedSender<Bytes>>) -> Self {
         ProcMsg::AddProc {
-            proce: proce.clone(),
-            rest_message: restmessage,
-            uuid: uuid.to_string()
+            proce: Box::new(proce.clone()),
+            rest_message: Box::new(restmessage),
+            uuid: uuid.to_string(),
+            exited,
+            stdout_stream
         }
     }
     fn new_infos(uuid: &str, tx: Sender<Option<ProcessInfos>>) -> Self {
@@ -85,24 +127,112 @@ pub struct ProcessInfos {
     stop_ms: u128,
     resources: Option<ResUse>,
     was_killed: bool,
-    stdout: String,
-    stderr: String,
+    stdout: Captured,
+    stderr: Captured,
+    cgroup: Option<CGroupStats>,
+    /// the signal that ended the process, if any
+    signal: Option<i32>,
+}
+
+/// ProcessExit is sent back when the process ends:
+/// its exit code, or the signal that terminated it, and the stderr
+#[derive(Default, Debug, Clone)]
+pub struct ProcessExit {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub was_killed: bool,
+    /// the stdout as text for the logs (lossy UTF-8, a final newline removed)
+    pub stdout: String,
+    /// the stdout as written, the reply body
+    pub stdout_raw: Bytes,
+    /// stdout was longer than `maxoutput`: `stdout_raw` is not complete
+    pub truncated: bool,
+    pub stderr: String,
+    /// the process could not be spawned or waited
+    pub error: Option<String>,
+    /// the cgroup limit that made the process fail (`memory.max`, `pids.max`)
+    pub limit: Option<String>,
 }
 
 type AtomicHash = Arc<TMutex<HashMap<String, ProcessInfos>>>;
+/// the kill time (ms since epoch) of the running processes
+type KillTimers = Arc<std::sync::Mutex<HashMap<String, Arc<AtomicU64>>>>;
+
+/// Write a new file readable by the owner only: the temporary dir is shared
+async fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path).await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
 
-fn spawn_proce(proce: ProcEnv, proc_infos: AtomicHash, uuid: String, placeholdersreal: HashMap<String,String>) -> () {
+fn spawn_proce(proce: ProcEnv, proc_infos: AtomicHash, kill_timers: KillTimers, uuid: String, rest_message: Box<RestMessage>, exited: oneshot::Sender<ProcessExit>, stdout_stream: Option<mpsc::UnboundedSender<Bytes>>) -> () {
     let _ = tokio::spawn(async move {
         let start_ms = get_now_ms();
         let timeout = proce.timeout.unwrap_or(1000);
+        let wd = match proce.work_dir() {
+            Ok(wd) => wd,
+            Err(e) => {
+                warn!("Wd ERROR {} {}", uuid, e);
+                let _ = exited.send(ProcessExit { error: Some(e), ..Default::default() });
+                return;
+            }
+        };
+        let mut placeholdersreal = rest_message.placeholders();
+        let body = rest_message.body().clone();
+        let payload_file = if proce.encoding == Encoding::File {
+            let path = std::env::temp_dir().join(format!("urocket-{}.body", uuid));
+            if let Err(e) = write_private(&path, &body).await {
+                warn!("Payload file ERROR {:?} {}", path, e);
+                let _ = exited.send(ProcessExit { error: Some(format!("payload file error: {}", e)), ..Default::default() });
+                return;
+            }
+            Some(path)
+        } else {
+            None
+        };
+        let payload = match proce.encoding {
+            Encoding::Json | Encoding::Raw => rest_message.body_text().to_string(),
+            Encoding::Base64 => base64::engine::general_purpose::STANDARD.encode(&body),
+            Encoding::File => payload_file.as_ref().map(|p| p.to_string_lossy().to_string()).unwrap_or_default(),
+        };
+        let stdin_data = match proce.encoding {
+            Encoding::Json | Encoding::Raw => body,
+            Encoding::Base64 | Encoding::File => Bytes::from(payload.clone()),
+        };
+        placeholdersreal.insert("payload".to_string(), payload);
         let mut placeholders2: HashMap<&str, &str> = HashMap::new();
         for (ph, val) in placeholdersreal.iter() {
             placeholders2.insert(ph, val);
         }
+        let cgroup = match proce.limits.as_ref().map(|limits| CGroup::create(limits, &uuid)) {
+            Some(Err(e)) => {
+                warn!("Cgroup ERROR {} {}", uuid, e);
+                if let Some(path) = &payload_file {
+                    let _ = std::fs::remove_file(path);
+                }
+                let _ = exited.send(ProcessExit { error: Some(format!("cgroup error: {}", e)), ..Default::default() });
+                return;
+            }
+            Some(Ok(cgroup)) => Some(cgroup),
+            None => None,
+        };
         let cmd_and_args = proce.cmd_to_arr_replacements(&placeholders2);
         let comma = format!("Cmd{}: {:?}",&uuid, cmd_and_args);
         let mut cmd_ex = Command::new(&cmd_and_args[0]);
+        if let Some(inherited) = proce.inherited_env() {
+            cmd_ex.env_clear();
+            cmd_ex.envs(inherited);
+        }
+        if let Some(wd) = wd {
+            cmd_ex.current_dir(wd);
+        }
         cmd_ex.env("REQUEST_ID", uuid.clone());
+        cmd_ex.env("URIPATH", rest_message.uri());
+        cmd_ex.env("METHOD", rest_message.method().as_str());
+        cmd_ex.envs(rest_message.request_env());
+        if let Some(path) = &payload_file {
+            cmd_ex.env("PAYLOAD_FILE", path);
+        }
         for argx in cmd_and_args.iter().skip(1) {
             cmd_ex.arg(argx);
         }
@@ -111,15 +241,74 @@ fn spawn_proce(proce: ProcEnv, proc_infos: AtomicHash, uuid: String, placeholder
         }
         cmd_ex.stderr(Stdio::piped());
         cmd_ex.stdout(Stdio::piped());
-        let mut child = cmd_ex.spawn().unwrap();
+        // its own group: the timeout kills the grandchildren too
+        cmd_ex.process_group(0);
+        if proce.channel == Channel::Stdin || proce.encoding == Encoding::Raw {
+            cmd_ex.stdin(Stdio::piped());
+        }
+        // opened here, the child only writes `0` in it between fork and exec
+        let cgroup_procs = match cgroup.as_ref().map(|cg| std::fs::OpenOptions::new().write(true).open(cg.procs())) {
+            Some(Err(e)) => {
+                warn!("Cgroup ERROR {} {}", uuid, e);
+                if let Some(path) = &payload_file {
+                    let _ = std::fs::remove_file(path);
+                }
+                if let Some(cg) = cgroup {
+                    cg.release();
+                }
+                let _ = exited.send(ProcessExit { error: Some(format!("cgroup error: {}", e)), ..Default::default() });
+                return;
+            }
+            Some(Ok(procs)) => Some(procs),
+            None => None,
+        };
+        if let Some(procs) = &cgroup_procs {
+            let fd = procs.as_raw_fd();
+            unsafe {
+                cmd_ex.pre_exec(move || {
+                    if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let spawned = cmd_ex.spawn();
+        drop(cgroup_procs);
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Spawn ERROR {comma} {}", e);
+                if let Some(path) = &payload_file {
+                    let _ = std::fs::remove_file(path);
+                }
+                if let Some(cg) = cgroup {
+                    cg.release();
+                }
+                let _ = exited.send(ProcessExit { error: Some(format!("spawn error: {}", e)), ..Default::default() });
+                return;
+            }
+        };
         
         let pid = child.id();
-        let in_millis = std::time::Duration::from_millis(timeout as u64);
-        let eutanasia = std::thread::spawn(move || {
-            // sleep for at least the specified amount of time
-            std::thread::sleep(in_millis);
-            unsafe { libc::kill(pid as i32, libc::SIGTERM) }
-        });
+        if let Some(child_stdin) = child.stdin.take() {
+            // written by a task: the child could fill stdout before reading all of stdin
+            match pipe::Sender::from_file(std::fs::File::from(OwnedFd::from(child_stdin))) {
+                Ok(mut child_stdin) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = child_stdin.write_all(&stdin_data).await {
+                            trace!("Pid({pid}) stdin write error {}", e);
+                        }
+                        // child_stdin is dropped here, closing the pipe
+                    });
+                }
+                Err(e) => warn!("Pid({pid}) stdin pipe error {}", e),
+            }
+        }
+        let pidfd = PidFd::open(pid);
+        let kill_at = Arc::new(AtomicU64::new(get_now_ms() as u64 + timeout as u64));
+        kill_timers.lock().unwrap().insert(uuid.clone(), kill_at.clone());
+        let killgrace = proce.killgrace.unwrap_or(DEFAULT_KILL_GRACE) as u64;
         let child_stdout = child
         .stdout
         .take()
@@ -128,27 +317,78 @@ fn spawn_proce(proce: ProcEnv, proc_infos: AtomicHash, uuid: String, placeholder
         .stderr
         .take()
         .expect("Internal error, could not take stderr");
-        let stdout_lines = BufReader::new(child_stdout).lines();
-        let stderr_lines = BufReader::new(child_stderr).lines();
-        let stdout_buf = stdout_lines.map(|x|{
-            match x {
-                Ok(s) => s,
-                Err(e) => format!("EE: {:?}",e)
+        // both drained at once: the child can fill stderr while stdout is still open
+        let cap = proce.maxoutput.unwrap_or(DEFAULT_MAX_OUTPUT);
+        let spill = |name: &str| proce.spilloutput.then(|| std::env::temp_dir().join(format!("urocket-{}.{}", uuid, name)));
+        let (spill_stdout, spill_stderr) = (spill("stdout"), spill("stderr"));
+        let (stop_output, output_stop) = watch::channel(false);
+        let output = async {
+            tokio::join!(
+                capture(std::fs::File::from(OwnedFd::from(child_stdout)), cap, spill_stdout, stdout_stream, output_stop.clone()),
+                capture(std::fs::File::from(OwnedFd::from(child_stderr)), cap, spill_stderr, None, output_stop)
+            )
+        };
+        let mut killed_with = None;
+        let (stdout_cap, stderr_cap) = {
+            // done when the process exited and its output is over (grandchildren can keep it open)
+            let finished = async { tokio::join!(output, pidfd.exited()).0 };
+            tokio::pin!(finished);
+            tokio::select! {
+                done = &mut finished => done,
+                _ = kill_time(&kill_at) => {
+                    pidfd.signal_group(libc::SIGTERM);
+                    killed_with = Some(libc::SIGTERM);
+                    tokio::select! {
+                        done = &mut finished => done,
+                        _ = tokio::time::sleep(tokio::time::Duration::from_millis(killgrace)) => {
+                            warn!("Pid({pid}) still running {killgrace}ms after SIGTERM, SIGKILL");
+                            pidfd.signal_group(libc::SIGKILL);
+                            killed_with = Some(libc::SIGKILL);
+                            match tokio::time::timeout(tokio::time::Duration::from_millis(OUTPUT_GRACE), &mut finished).await {
+                                Ok(done) => done,
+                                Err(_) => {
+                                    warn!("Pid({pid}) output still open {OUTPUT_GRACE}ms after SIGKILL, leaving it");
+                                    stop_output.send_replace(true);
+                                    finished.await
+                                }
+                            }
+                        }
+                    }
+                }
             }
-        }).collect::<Vec<String>>().join("\n");
-        let stderr_buf = stderr_lines.map(|x|{
-            match x {
-                Ok(s) => s,
-                Err(e) => format!("EE: {:?}",e)
+        };
+        // stdout is over: the stream (moved into capture) ended before the exit is notified.
+        // The process exited: wait4 reaps it at once, with its ResUse
+        let waited = child.wait4();
+        drop(pidfd);
+        kill_timers.lock().unwrap().remove(&uuid);
+        if let Some(path) = &payload_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                trace!("Pid({pid}) payload file remove error {}", e);
+            }
+        }
+        let cgroup_stats = cgroup.map(|cg| cg.release());
+        for (name, captured) in [("stdout", &stdout_cap), ("stderr", &stderr_cap)] {
+            if captured.truncated {
+                warn!("Pid({pid}) {name} truncated at {} of {} bytes, spill: {:?}", captured.bytes.len(), captured.total, captured.spill);
             }
-        }).collect::<Vec<String>>().join("\n");
-        match child.wait4() {
+        }
+        let spills = stdout_cap.spill.iter().chain(stderr_cap.spill.iter()).cloned().collect();
+        expire_spills(spills, proce.spillttl.unwrap_or(DEFAULT_SPILL_TTL));
+        match waited {
             Ok(ruse)=> {
                 let stop_ms = get_now_ms();
-                let was_killed = if eutanasia.is_finished() {
-                    eutanasia.join().unwrap() != -1
-                } else {
-                    false
+                let was_killed = killed_with.is_some();
+                let exit = ProcessExit {
+                    code: ruse.status.code(),
+                    signal: ruse.status.signal(),
+                    was_killed,
+                    stdout: stdout_cap.text(),
+                    stdout_raw: stdout_cap.bytes.clone(),
+                    truncated: stdout_cap.truncated,
+                    stderr: stderr_cap.text(),
+                    error: None,
+                    limit: cgroup_stats.as_ref().and_then(|stats| stats.limit_hit()),
                 };
                 let b = proc_infos.clone();
                 {
@@ -158,17 +398,21 @@ fn spawn_proce(proce: ProcEnv, proc_infos: AtomicHash, uuid: String, placeholder
                         pid,
                         start_ms,
                         stop_ms,
+                        signal: ruse.status.signal(),
                         resources: Some(ruse),
                         was_killed,
-                        stdout: stdout_buf,
-                        stderr: stderr_buf,
+                        stdout: stdout_cap,
+                        stderr: stderr_cap,
+                        cgroup: cgroup_stats,
                     };
                     (*infos).insert(uuid.to_string(), pi);
                     drop(infos);
                 }
+                let _ = exited.send(exit);
             }
             Err(e) => {
                 trace!("Execution ERROR Pid({pid}) {comma}{}", e);
+                let _ = exited.send(ProcessExit { error: Some(format!("wait error: {}", e)), ..Default::default() });
             }
         };
         let _selfclean = tokio::spawn(async move {
@@ -186,13 +430,18 @@ fn spawn_proce(proce: ProcEnv, proc_infos: AtomicHash, uuid: String, placeholder
 struct ProcessControllerActor {
     receiver: mpsc::Receiver<ProcMsg>,
     proc_infos: AtomicHash,
+    kill_timers: KillTimers,
+    /// the worker pools, by their ProcEnv
+    pools: HashMap<String, WorkerPool>,
 }
 
 impl ProcessControllerActor {
     pub fn new(receiver: mpsc::Receiver<ProcMsg>) -> Self {
         ProcessControllerActor {
             receiver,
-            proc_infos: Arc::new(TMutex::new(HashMap::new()))
+            proc_infos: Arc::new(TMutex::new(HashMap::new())),
+            kill_timers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            pools: HashMap::new(),
         }
     }
 
@@ -202,13 +451,71 @@ impl ProcessControllerActor {
         }
     }
 
+    /// The pool of the ProcEnv, started on first use
+    fn pool(&mut self, proce: &ProcEnv) -> WorkerPool {
+        let key = serde_json::to_string(proce).unwrap_or_default();
+        self.pools.entry(key).or_insert_with(|| WorkerPool::start(proce)).clone()
+    }
+
+    /// Serve the request with a pool worker (`pool`): as spawn_proce, the ProcessInfos
+    /// are stored and the exit is sent when the worker answers
+    fn run_in_pool(&mut self, proce: &ProcEnv, uuid: String, rest_message: Box<RestMessage>, exited: oneshot::Sender<ProcessExit>, stdout_stream: Option<mpsc::UnboundedSender<Bytes>>) {
+        let timeout = proce.timeout.unwrap_or(1000);
+        let pool = self.pool(proce);
+        let proc_infos = self.proc_infos.clone();
+        let kill_timers = self.kill_timers.clone();
+        let kill_at = Arc::new(AtomicU64::new(get_now_ms() as u64 + timeout as u64));
+        kill_timers.lock().unwrap().insert(uuid.clone(), kill_at.clone());
+        let (done_tx, done_rx) = oneshot::channel();
+        pool.submit(PoolJob { uuid: uuid.clone(), request: request_line(&uuid, &rest_message), kill_at, done: done_tx });
+        tokio::spawn(async move {
+            let done = done_rx.await;
+            kill_timers.lock().unwrap().remove(&uuid);
+            let done = match done {
+                Ok(done) => done,
+                Err(_) => {
+                    let _ = exited.send(ProcessExit { error: Some(String::from("worker pool lost the request")), ..Default::default() });
+                    return;
+                }
+            };
+            if let Some(tx) = stdout_stream {
+                // the answer is streamed in one chunk
+                let _ = tx.send(done.exit.stdout_raw.clone());
+            }
+            let pi = ProcessInfos {
+                uuid: uuid.clone(),
+                pid: done.pid,
+                start_ms: done.start_ms,
+                stop_ms: done.stop_ms,
+                was_killed: done.exit.was_killed,
+                signal: done.exit.signal,
+                stdout: Captured { bytes: done.exit.stdout_raw.clone(), total: done.exit.stdout_raw.len(), ..Default::default() },
+                ..Default::default()
+            };
+            proc_infos.lock().await.insert(uuid.clone(), pi);
+            let _ = exited.send(done.exit);
+            tokio::time::sleep(tokio::time::Duration::from_millis((timeout as u64) + 4000)).await;
+            proc_infos.lock().await.remove(&uuid);
+        });
+    }
+
     fn handle_message(&mut self, msg: ProcMsg) {
         match msg {
-            ProcMsg::AddProc { proce, rest_message, uuid } => {
-                let proc_infos = self.proc_infos.clone();
-                let mut placeholders = HashMap::new();
-                placeholders.insert("jsonpayload".to_string(), rest_message.body().to_string());
-                spawn_proce(proce, proc_infos, uuid, placeholders);
+            ProcMsg::AddProc { proce, rest_message, uuid, exited, stdout_stream } => {
+                if proce.pool.is_some() {
+                    self.run_in_pool(&proce, uuid, rest_message, exited, stdout_stream);
+                } else {
+                    let proc_infos = self.proc_infos.clone();
+                    spawn_proce(*proce, proc_infos, self.kill_timers.clone(), uuid, rest_message, exited, stdout_stream);
+                }
+            }
+            ProcMsg::StartPool { proce } => {
+                self.pool(&proce);
+            }
+            ProcMsg::Extend { uuid, by_ms } => {
+                if let Some(kill_at) = self.kill_timers.lock().unwrap().get(&uuid) {
+                    kill_at.fetch_max(get_now_ms() as u64 + by_ms, Ordering::SeqCst);
+                }
             }
             ProcMsg::GetInfos { uuid, tx } => {
                 // return process infos, and resource usage
@@ -228,14 +535,74 @@ impl ProcessControllerActor {
     }
 }
 
+impl ProcessInfos {
+    /// The infos as JSON: pid, start and stop time, wall time,
+    /// resource usage, stdout and stderr length, the cgroup peak memory and limit hit
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut infos = serde_json::json!({
+            "pid": self.pid,
+            "start_ms": self.start_ms as u64,
+            "stop_ms": self.stop_ms as u64,
+            "wall_ms": self.stop_ms.saturating_sub(self.start_ms) as u64,
+            "was_killed": self.was_killed,
+            "signal": self.signal,
+            "stdout_len": self.stdout.total,
+            "stderr_len": self.stderr.total,
+        });
+        for (name, captured) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if captured.truncated {
+                infos[format!("{}_truncated", name)] = serde_json::Value::Bool(true);
+            }
+            if let Some(spill) = &captured.spill {
+                infos[format!("{}_spill", name)] = serde_json::Value::String(spill.to_string_lossy().to_string());
+            }
+        }
+        if let Some(ruse) = &self.resources {
+            infos["utime_ms"] = serde_json::Value::from(ruse.rusage.utime.as_millis() as u64);
+            infos["stime_ms"] = serde_json::Value::from(ruse.rusage.stime.as_millis() as u64);
+            // in bytes: wait4 converts the kernel KB
+            infos["maxrss"] = serde_json::Value::from(ruse.rusage.maxrss);
+        }
+        if let Some(cgroup) = &self.cgroup {
+            infos["memory_peak"] = serde_json::json!(cgroup.memory_peak);
+            infos["oom_kills"] = serde_json::Value::from(cgroup.oom_kills);
+            infos["limit"] = serde_json::json!(cgroup.limit_hit());
+        }
+        infos
+    }
+}
+
 actor_handler!({} => ProcessControllerActor, ProcessController, ProcMsg);
 
 impl ProcessController {
-    pub async fn run_back_process(&self, proce: &ProcEnv, req: RestMessage, uuid: &str) -> () {
-        let msg = ProcMsg::new_proc(proce, req, uuid);
+    /// Spawn the process for the request, the returned receiver gets
+    /// the ProcessExit when the process ends
+    pub async fn run_back_process(&self, proce: &ProcEnv, req: RestMessage, uuid: &str) -> oneshot::Receiver<ProcessExit> {
+        self.run_back_process_streaming(proce, req, uuid, None).await
+    }
+
+    /// As run_back_process, each stdout line (with its newline) is
+    /// also sent to `stdout_stream` as soon as it is read
+    pub async fn run_back_process_streaming(&self, proce: &ProcEnv, req: RestMessage, uuid: &str, stdout_stream: Option<mpsc::UnboundedSender<Bytes>>) -> oneshot::Receiver<ProcessExit> {
+        let (tx, rx) = oneshot::channel();
+        let msg = ProcMsg::new_proc(proce, req, uuid, tx, stdout_stream);
         match toktor_send!(self,msg).await {
             _ => {}
         };
+        rx
+    }
+
+    /// Start the workers of a `pool` ProcEnv, before its first request
+    pub async fn start_pool(&self, proce: &ProcEnv) {
+        let msg = ProcMsg::StartPool { proce: Box::new(proce.clone()) };
+        let _ = toktor_send!(self, msg).await;
+    }
+
+    /// Push back the kill time of the running process, to
+    /// `by_ms` from now (it is never brought forward)
+    pub async fn extend(&self, uuid: &str, by_ms: u64) {
+        let msg = ProcMsg::Extend { uuid: uuid.to_string(), by_ms };
+        let _ = toktor_send!(self, msg).await;
     }
 
     pub async fn get_infos(&self, uuid: &str, tx: Sender<Option<ProcessInfos>>) -> () {
@@ -250,6 +617,7 @@ impl ProcessController {
 mod tests {
     use crate::toktor_new;
     use super::*;
+    use crate::procenv::EnvMode;
     
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn run_process_controller() {
@@ -281,6 +649,220 @@ mod tests {
         println!("the time is over");
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_wd_and_env_mode() {
+        let proco = toktor_new!(ProcessController);
+        let script = ["/bin/sh", "-c", "echo $(pwd) $METHOD $URIPATH $REQUEST_ID home:$HOME my:$MYENV >&2"];
+        let mut proce = ProcEnv::new_v("/tmp", vec!["MYENV=provolone"], &script, "");
+        let exit = proco.run_back_process(&proce, RestMessage::new("POST", "/env/in", ""), "ENV-INHERIT").await.await.unwrap();
+        let home = std::env::var("HOME").unwrap_or_default();
+        assert_eq!(exit.stderr, format!("/tmp POST /env/in ENV-INHERIT home:{home} my:provolone"));
+        proce.envmode = EnvMode::Clean;
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/env/in", ""), "ENV-CLEAN").await.await.unwrap();
+        assert_eq!(exit.stderr, "/tmp GET /env/in ENV-CLEAN home: my:provolone");
+        proce.envmode = EnvMode::Allowlist;
+        proce.envallow = vec![String::from("HOME")];
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/env/in", ""), "ENV-ALLOW").await.await.unwrap();
+        assert_eq!(exit.stderr, format!("/tmp GET /env/in ENV-ALLOW home:{home} my:provolone"));
+        // PATH is kept: a command without path is found
+        let mut bare = ProcEnv::new("", vec![], "printenv PATH", "");
+        bare.envmode = EnvMode::Clean;
+        let exit = proco.run_back_process(&bare, RestMessage::new("GET", "/env/in", ""), "ENV-PATH").await.await.unwrap();
+        assert_eq!(exit.code, Some(0));
+        assert_eq!(exit.stdout, std::env::var("PATH").unwrap());
+        proce.wd = String::from("/not/existent/dir");
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/env/in", ""), "ENV-WD").await.await.unwrap();
+        assert!(exit.error.unwrap().contains("/not/existent/dir"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_placeholder_arguments() {
+        let proco = toktor_new!(ProcessController);
+        // a client value stays one argument: the `;` does not run a second command
+        let mut req = RestMessage::new("GET", "/search", "");
+        req.set_query("q=a%3B%20echo%20pwned");
+        let proce = ProcEnv::new("", vec![], "echo {{query.q}}", "");
+        let exit = proco.run_back_process(&proce, req, "ARG-SPLIT").await.await.unwrap();
+        assert_eq!(exit.stdout, "a; echo pwned");
+        let mut req = RestMessage::new("GET", "/search", "");
+        req.set_query("q=a%3B%20echo%20pwned");
+        let proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo \"$1\"", "_", "{{query.q}}"], "");
+        let exit = proco.run_back_process(&proce, req, "ARG-SH").await.await.unwrap();
+        assert_eq!(exit.stdout, "a; echo pwned");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_exit_status() {
+        let proco = toktor_new!(ProcessController);
+        let req = RestMessage::new("POST", "/put/staff/in", "");
+        let proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo broken >&2; exit 3"], "");
+        let exit = proco.run_back_process(&proce, req, "EXIT-STATUS").await.await.unwrap();
+        assert_eq!(exit.code, Some(3));
+        assert_eq!(exit.signal, None);
+        assert_eq!(exit.stderr, "broken");
+        let req = RestMessage::new("POST", "/put/staff/in", "");
+        let proce = ProcEnv::new("", vec![], "/not/existent/command", "");
+        let exit = proco.run_back_process(&proce, req, "EXIT-SPAWN").await.await.unwrap();
+        assert!(exit.error.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_stdin_channel() {
+        let proco = toktor_new!(ProcessController);
+        let req = RestMessage::new("POST", "/put/staff/in", "{\"from\": \"stdin\"}");
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "cat >&2"], "");
+        proce.channel = Channel::Stdin;
+        let exit = proco.run_back_process(&proce, req, "EXIT-STDIN").await.await.unwrap();
+        assert_eq!(exit.code, Some(0));
+        assert_eq!(exit.stderr, "{\"from\": \"stdin\"}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_payload_encoding() {
+        let proco = toktor_new!(ProcessController);
+        let image = Bytes::from_static(&[0x89, b'P', b'N', b'G', 0xff, 0x00, 0xfe]);
+        let mut req = RestMessage::new("POST", "/pet/1/uploadImage", "");
+        req.set_body(image.clone());
+        let proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "od -An -tx1 | tr -d ' \\n' >&2"], "raw");
+        let exit = proco.run_back_process(&proce, req, "ENC-RAW").await.await.unwrap();
+        assert_eq!(exit.stderr, "89504e47ff00fe");
+        let mut req = RestMessage::new("POST", "/pet/1/uploadImage", "");
+        req.set_body(image.clone());
+        let proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo {{payload}} >&2"], "base64");
+        let exit = proco.run_back_process(&proce, req, "ENC-BASE64").await.await.unwrap();
+        assert_eq!(exit.stderr, "iVBOR/8A/g==");
+        let mut req = RestMessage::new("POST", "/pet/1/uploadImage", "");
+        req.set_body(image.clone());
+        let proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "test \"$PAYLOAD_FILE\" = {{payload}} && od -An -tx1 {{payload}} | tr -d ' \\n' >&2"], "file");
+        let exit = proco.run_back_process(&proce, req, "ENC-FILE").await.await.unwrap();
+        assert_eq!(exit.code, Some(0));
+        assert_eq!(exit.stderr, "89504e47ff00fe");
+        assert!(!std::env::temp_dir().join("urocket-ENC-FILE.body").exists());
+        let proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "stat -c %a {{payload}} >&2"], "file");
+        let exit = proco.run_back_process(&proce, RestMessage::new("POST", "/upload", "secret"), "ENC-FILE-MODE").await.await.unwrap();
+        assert_eq!(exit.stderr, "600");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_extend() {
+        let proco = toktor_new!(ProcessController);
+        let req = RestMessage::new("POST", "/put/staff/in", "");
+        let mut proce = ProcEnv::new("", vec![], "sleep 1", "");
+        proce.timeout = Some(300);
+        let exited = proco.run_back_process(&proce, req, "EXTEND").await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        proco.extend("EXTEND", 2000).await;
+        let exit = exited.await.unwrap();
+        assert_eq!(exit.code, Some(0));
+        assert!(!exit.was_killed);
+    }
+
+    #[tokio::test]
+    async fn process_many_concurrent() {
+        // a single thread runtime: the processes run side by side, none blocks it
+        let proco = toktor_new!(ProcessController);
+        let proce = ProcEnv::new("", vec![], "sleep 0.3", "");
+        let started = std::time::Instant::now();
+        let mut exits = Vec::new();
+        for n in 0..20 {
+            exits.push(proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), &format!("MANY-{n}")).await);
+        }
+        for exited in exits {
+            assert_eq!(exited.await.unwrap().code, Some(0));
+        }
+        assert!(started.elapsed().as_millis() < 2000);
+    }
+
+    #[tokio::test]
+    async fn process_output_capture() {
+        // a single thread runtime: the capture must not block it
+        let proco = toktor_new!(ProcessController);
+        // stderr fills its pipe before anything is written on stdout
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "head -c 200000 /dev/zero | tr '\\0' e >&2; echo ok"], "");
+        proce.timeout = Some(5000);
+        proce.maxoutput = Some(1000);
+        proce.spilloutput = true;
+        proce.spillttl = Some(300);
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), "CAPTURE").await.await.unwrap();
+        assert_eq!(exit.stdout, "ok");
+        assert!(exit.stderr.starts_with(&"e".repeat(1000)));
+        assert_eq!(exit.stderr.len(), 1000);
+        assert!(!exit.truncated);
+        let (tx, mut rx) = mpsc::channel(1);
+        proco.get_infos("CAPTURE", tx).await;
+        let infos = rx.recv().await.flatten().unwrap().to_json();
+        assert_eq!(infos["stderr_len"], 200000);
+        assert_eq!(infos["stderr_truncated"], true);
+        let spill = infos["stderr_spill"].as_str().unwrap().to_string();
+        assert_eq!(std::fs::metadata(&spill).unwrap().len(), 199000);
+        // removed after the spillttl
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        assert!(!std::path::Path::new(&spill).exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_group_kill() {
+        let proco = toktor_new!(ProcessController);
+        // the grandchild keeps stdout open: the whole group must go
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "sleep 30 & sleep 30"], "");
+        proce.timeout = Some(200);
+        let started = std::time::Instant::now();
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), "GROUP-TERM").await.await.unwrap();
+        assert!(exit.was_killed);
+        assert_eq!(exit.signal, Some(libc::SIGTERM));
+        assert!(started.elapsed().as_millis() < 2000);
+        // SIGTERM is ignored: SIGKILL after the grace period
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "trap '' TERM; sleep 30 & sleep 30"], "");
+        proce.timeout = Some(200);
+        proce.killgrace = Some(300);
+        let started = std::time::Instant::now();
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), "GROUP-KILL").await.await.unwrap();
+        assert!(exit.was_killed);
+        assert_eq!(exit.signal, Some(libc::SIGKILL));
+        let elapsed = started.elapsed().as_millis();
+        assert!((500..2500).contains(&elapsed), "{elapsed}");
+        let (tx, mut rx) = mpsc::channel(1);
+        proco.get_infos("GROUP-KILL", tx).await;
+        let infos = rx.recv().await.flatten().unwrap().to_json();
+        assert_eq!(infos["signal"], libc::SIGKILL);
+        // a descendant out of the group keeps stdout open: left after the output grace
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "setsid sleep 5 & sleep 30"], "");
+        proce.timeout = Some(200);
+        proce.killgrace = Some(100);
+        let started = std::time::Instant::now();
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), "GROUP-SETSID").await.await.unwrap();
+        assert!(exit.was_killed);
+        let elapsed = started.elapsed().as_millis();
+        assert!((1300..3000).contains(&elapsed), "{elapsed}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn process_limits() {
+        let proco = toktor_new!(ProcessController);
+        // a plain directory stands for the delegated cgroup subtree
+        let root = std::env::temp_dir().join(format!("urocket-limits-{}", std::process::id()));
+        let cgroup = root.join("shared");
+        std::fs::create_dir_all(&cgroup).unwrap();
+        std::fs::write(cgroup.join("cgroup.procs"), "").unwrap();
+        let mut proce = ProcEnv::new("", vec![], "echo limited", "");
+        proce.limits = Some(crate::procenv::Limits {
+            pids_max: Some(crate::procenv::LimitValue::Number(8)),
+            root: Some(root.to_string_lossy().to_string()),
+            group: Some(String::from("shared")),
+            ..Default::default()
+        });
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), "LIMITS").await.await.unwrap();
+        assert_eq!(exit.code, Some(0));
+        assert_eq!(exit.limit, None);
+        assert_eq!(std::fs::read_to_string(cgroup.join("pids.max")).unwrap(), "8");
+        assert_eq!(std::fs::read_to_string(cgroup.join("cgroup.procs")).unwrap(), "0");
+        std::fs::remove_dir_all(&root).unwrap();
+        // without a writable subtree the process is not spawned
+        proce.limits.as_mut().unwrap().root = Some(String::from("/proc/urocket-none"));
+        let exit = proco.run_back_process(&proce, RestMessage::new("GET", "/", ""), "NOCGROUP").await.await.unwrap();
+        assert!(exit.error.unwrap().starts_with("cgroup error"));
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn process_get_infos() {
         let proco = toktor_new!(ProcessController);
@@ -1,41 +1,183 @@
 use std::collections::HashMap;
 
-use serde::{Deserialize, Serialize};
-use tokio::sync::{mpsc, oneshot::{Receiver, Sender, self}};
+use bytes::Bytes;
+use tokio::sync::{mpsc, watch, oneshot::{Receiver, Sender, self}};
 
 use std::sync::Arc;
 use tokio::sync::Mutex as TMutex;
 
+use tokio::time::{Duration, Instant};
 use tracing::{warn, info};
+use hyper::Method;
 
 extern crate toktor;
 use toktor::actor_handler;
-use crate::{toktor_send, serviceconf::ServiceConf, processcontroller::ProcessController};
+
+use crate::{toktor_send, serviceconf::{ServiceConf, RouteMatch, ReplyMode, StreamFormat, VerbAction, ValidateOutPolicy}, processcontroller::{ProcessController, ProcessExit}};
 
 use crate::restmessage::RestMessage;
+use crate::procenv::ProcEnv;
+use crate::openapi::{OpenApi, Violations};
+use crate::jobs::{JobStore, DEFAULT_JOB_TTL, JOBS_PATH};
+use crate::webhook::{Webhook, CALLBACK_HEADER, DEFAULT_BACKOFF, DEFAULT_DELIVERY_TIMEOUT, DEFAULT_RETRIES};
+use crate::admission::{Admission, Permit};
 
 
-#[derive(Default,Serialize,Deserialize,Debug,Clone,PartialEq)]
+#[derive(Default,Debug,Clone,PartialEq)]
 pub struct ForHttpResponse {
     pub code: u32,
-    pub data: serde_json::Value,
+    /// the reply body, as sent by the process
+    pub body: Bytes,
+    /// response headers: the reply content type and the ones
+    /// set by the process (`X-Urocket-Header-*`)
+    pub headers: Vec<(String,String)>,
+}
+
+impl ForHttpResponse {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+            .map(|(_, v)| v.as_str())
+    }
 }
 
 pub enum FrontResponse {
     BackMsg(ForHttpResponse),
+    /// the process stdout (`reply: stdout`), `headers` are added to the content type
+    Output { code: u16, content_type: String, headers: Vec<(String,String)>, body: Bytes },
+    /// a streamed reply (`stream`), it ends when `chunks` is closed; an error
+    /// aborts it, the client sees the truncation
+    Stream { code: u16, headers: Vec<(String,String)>, chunks: mpsc::Receiver<StreamChunk> },
+    /// an `async` request was started, the job id
+    Accepted(String),
+    Timeout(String),
+    BadGateway(String),
+    /// too many requests: the process queue is full, or the wait expired
+    Unavailable { retry_after: u64, body: String },
+    NotFound,
+    MethodNotAllowed(Vec<Method>),
     InternalError,
 }
 
+/// a chunk of a streamed reply, or the error aborting it
+pub type StreamChunk = Result<Bytes, std::io::Error>;
+
+/// default request deadline, in ms
+const DEFAULT_DEADLINE: u64 = 40000;
+const DEFAULT_TIMEOUT_BODY: &str = "Gateway Timeout";
+/// max stderr bytes reported in the 502 body (debug mode only)
+const STDERR_REPORT_LEN: usize = 2048;
+/// chunks of a streamed reply buffered toward the client
+const STREAM_BUFFER: usize = 32;
+const DEFAULT_STREAM_CONTENT_TYPE: &str = "application/octet-stream";
+
+pub const STATS_HEADER: &str = "urocket-stats";
+
+type Subscriptions = Arc<TMutex<HashMap<String,Subscriber>>>;
+/// the process infos (`ProcessInfos::to_json`), once the process exits
+type StatsSlot = watch::Receiver<Option<serde_json::Value>>;
+
 struct Subscriber {
     request_id: String,
+    started: Instant,
+    /// when the request is evicted with 504, it can be pushed back (`extend`)
+    deadline: Instant,
+    /// the deadline can be pushed back up to `started` + maxdeadline
+    maxdeadline: Option<u64>,
+    /// the default extension, the route deadline
     timeout: u64,
-    respond_to: oneshot::Sender<FrontResponse>
+    respond_to: ReplyTo,
+    jsonreply: bool,
+    validateout: Option<OutValidation>,
+    stream: Option<StreamFormat>,
+    contenttype: Option<String>,
+    stats: Option<StatsSlot>,
+}
+
+impl Subscriber {
+    /// Push back the deadline to `by_ms` (default the route deadline) from now,
+    /// up to the route `maxdeadline`. Returns the new deadline, None if the
+    /// route has no `maxdeadline`
+    fn extend(&mut self, by_ms: Option<u64>) -> Option<Instant> {
+        let max = self.started + Duration::from_millis(self.maxdeadline?);
+        let until = (Instant::now() + Duration::from_millis(by_ms.unwrap_or(self.timeout))).min(max);
+        self.deadline = self.deadline.max(until);
+        info!("request {} deadline extended, {}ms left", self.request_id, self.deadline.saturating_duration_since(Instant::now()).as_millis());
+        Some(self.deadline)
+    }
+}
+
+/// Where the reply goes: the frontserv still waiting for the
+/// response, or the stream already started toward the client
+enum ReplyTo {
+    Front(oneshot::Sender<FrontResponse>),
+    Stream(mpsc::Sender<StreamChunk>),
+}
+
+impl ReplyTo {
+    /// Send the response if the frontserv is waiting, a started stream is just closed
+    fn send(self, response: FrontResponse) {
+        if let ReplyTo::Front(tx) = self {
+            let _ = tx.send(response);
+        }
+    }
+
+    /// Send the failure response if the frontserv is waiting, a started stream is
+    /// aborted: a truncated stream must not look like a complete one
+    async fn fail(self, response: FrontResponse, reason: &str) {
+        match self {
+            ReplyTo::Front(tx) => {
+                let _ = tx.send(response);
+            }
+            ReplyTo::Stream(chunks) => {
+                let _ = chunks.send(Err(std::io::Error::new(std::io::ErrorKind::TimedOut, reason.to_string()))).await;
+            }
+        }
+    }
+}
+
+/// The OpenAPI operation the process reply is validated against (`validateout`)
+struct OutValidation {
+    doc: Arc<OpenApi>,
+    route: String,
+    method: Method,
+    policy: ValidateOutPolicy,
+}
+
+impl OutValidation {
+    fn new(config: &ServiceConf, va: &VerbAction, req: &RestMessage) -> Option<Self> {
+        match &config.openapidoc {
+            Some(doc) if va.validateout => Some(OutValidation {
+                doc: doc.clone(),
+                route: req.route().to_string(),
+                method: req.method().clone(),
+                policy: va.validateoutpolicy.clone(),
+            }),
+            _ => None
+        }
+    }
+
+    /// The reply for the frontserv: the response itself, or
+    /// 502 if it violates the OpenAPI responses and the policy is `reject`
+    fn check(&self, req_id: &str, response: ForHttpResponse) -> FrontResponse {
+        let code = u16::try_from(response.code).unwrap_or(0);
+        match self.doc.validate_response(&self.route, &self.method, code, response.content_type(), &response.body) {
+            Ok(()) => FrontResponse::BackMsg(response),
+            Err(violations) => {
+                warn!("request {} reply violates the OpenAPI responses of {} {}: {}", req_id, self.method, self.route, violations.to_json());
+                match self.policy {
+                    ValidateOutPolicy::Reject => FrontResponse::BadGateway(violations.to_json()),
+                    ValidateOutPolicy::Record => FrontResponse::BackMsg(response)
+                }
+            }
+        }
+    }
 }
 
 enum ReqVisorMsg {
     RegisterPending {
         //req: Request<IncomingBody>,
-        req: RestMessage,
+        req: Box<RestMessage>,
         respond_to: Sender<(Receiver<FrontResponse>,String)>
     },
     FulfillPending {
@@ -44,24 +186,51 @@ enum ReqVisorMsg {
         respond_to: Sender<bool>
         // the response is true if req_id match some unfulfilled message
         // it is false elsewise
+    },
+    PushChunk {
+        req_id: String,
+        chunk: ForHttpResponse,
+        respond_to: Sender<bool>
+    },
+    Extend {
+        req_id: String,
+        by_ms: Option<u64>,
+        respond_to: Sender<bool>
     }
 }
 
 struct RequestsVisorActor {
     receiver: mpsc::Receiver<ReqVisorMsg>,
-    subscriptions: Arc<TMutex<HashMap<String,Subscriber>>>,
+    subscriptions: Subscriptions,
     pctl: ProcessController,
-    config: ServiceConf
+    config: ServiceConf,
+    jobs: JobStore,
+    admission: Admission,
 }
 
 impl RequestsVisorActor {
     pub fn new(receiver: mpsc::Receiver<ReqVisorMsg>, pctl: &ProcessController, conf: &ServiceConf) -> Self {
         //println!("REQUEST ACTOR:: {:?}",conf);
+        let pools: Vec<ProcEnv> = conf.paths.values()
+            .flat_map(|pv| pv.verb_actions())
+            .filter_map(|va| va.inject.clone().filter(|proce| proce.pool.is_some()))
+            .collect();
+        if !pools.is_empty() {
+            // the workers are started up front
+            let pctl = pctl.clone();
+            tokio::spawn(async move {
+                for proce in pools {
+                    pctl.start_pool(&proce).await;
+                }
+            });
+        }
         RequestsVisorActor {
             receiver,
             subscriptions: Arc::new(TMutex::new(HashMap::new())),
             pctl: pctl.clone(),
-            config: conf.clone()
+            config: conf.clone(),
+            jobs: JobStore::new(conf.jobttl.unwrap_or(DEFAULT_JOB_TTL)),
+            admission: Admission::new(conf),
         }
     }
 
@@ -73,38 +242,152 @@ impl RequestsVisorActor {
 
     fn handle_message(&mut self, msg: ReqVisorMsg) {
         match msg {
-            ReqVisorMsg::RegisterPending { req, respond_to } => {
+            ReqVisorMsg::RegisterPending { mut req, respond_to } => {
                 let subscriptions = self.subscriptions.clone();
                 let config = self.config.clone();
                 let pctl = self.pctl.clone();
+                let jobs = self.jobs.clone();
+                let admission = self.admission.clone();
                 tokio::spawn(async move {
                     match config.match_request(&req) {
-                        Some(va) => {
+                        RouteMatch::Found { action: va, route, params } => {
+                            req.set_route(&route, params);
+                            if let Err(violations) = validate_in(&config, &va, &req) {
+                                warn!("invalid request {} {}: {:?}", req.method(), req.uri(), violations);
+                                let (tx2, rx ) = oneshot::channel();
+                                let _ = tx2.send(FrontResponse::Output {
+                                    code: violations.code,
+                                    content_type: String::from("application/json"),
+                                    headers: vec![],
+                                    body: Bytes::from(violations.to_json())
+                                });
+                                let _ = respond_to.send((rx,String::from("")));
+                                return;
+                            }
+                            let callback = match callback_webhook(&config, &va, &req) {
+                                Ok(c) => c,
+                                Err(e) => {
+                                    warn!("invalid request {} {}: {}", req.method(), req.uri(), e);
+                                    let (tx2, rx ) = oneshot::channel();
+                                    let _ = tx2.send(FrontResponse::Output {
+                                        code: 400,
+                                        content_type: String::from("application/json"),
+                                        headers: vec![],
+                                        body: Bytes::from(serde_json::json!({"error": e}).to_string())
+                                    });
+                                    let _ = respond_to.send((rx,String::from("")));
+                                    return;
+                                }
+                            };
+                            let permit = match admission.acquire(&format!("{} {}", req.method(), route), va.maxconcurrent).await {
+                                Ok(permit) => permit,
+                                Err(refused) => {
+                                    warn!("request {} {} refused: {}", req.method(), req.uri(), refused);
+                                    let (tx2, rx ) = oneshot::channel();
+                                    let _ = tx2.send(FrontResponse::Unavailable {
+                                        retry_after: admission.retry_after(),
+                                        body: serde_json::json!({"error": refused.to_string()}).to_string()
+                                    });
+                                    let _ = respond_to.send((rx,String::from("")));
+                                    return;
+                                }
+                            };
+                            let asyncjob = va.asyncjob || callback.is_some();
                             let (tx, rx) = tokio::sync::oneshot::channel();
                             let uuid: String = uuid::Uuid::new_v4().to_string();
+                            let (stats_tx, stats) = if va.stats {
+                                let (stx, srx) = watch::channel(None);
+                                (Some(stx), Some(srx))
+                            } else {
+                                (None, None)
+                            };
+                            let timeout = va.deadline.unwrap_or(DEFAULT_DEADLINE);
+                            let started = Instant::now();
                             let msg_sub = Subscriber {
                                 request_id: uuid.clone(),
-                                timeout: 40000,
-                                respond_to: tx
+                                started,
+                                deadline: started + Duration::from_millis(timeout),
+                                maxdeadline: va.maxdeadline,
+                                timeout,
+                                respond_to: ReplyTo::Front(tx),
+                                jsonreply: va.jsonreply,
+                                validateout: OutValidation::new(&config, &va, &req),
+                                stream: va.stream.clone(),
+                                contenttype: va.contenttype.clone(),
+                                stats,
                             };
+                            let timeoutbody = va.timeoutbody.clone().unwrap_or(DEFAULT_TIMEOUT_BODY.to_string());
                             {
                                 let mut subscrs = subscriptions.lock().await;
                                 (*subscrs).insert(uuid.clone(), msg_sub);
                                 drop(subscrs);
                             }
+                            evict_on_deadline(subscriptions.clone(), pctl.clone(), &uuid, timeoutbody).await;
+                            if asyncjob {
+                                jobs.insert_pending(&uuid).await;
+                            }
+                            // the callback is delivered once the exit is collected
+                            let (exit_to, callback_exit) = match callback {
+                                Some(_) => {
+                                    let (etx, erx) = oneshot::channel();
+                                    (Some(etx), Some(erx))
+                                }
+                                None => (None, None)
+                            };
                             info!("associated action def {:?}", va.inject);
-                            if let Some(proce) = va.inject {
-                                pctl.run_back_process(&proce, req, &uuid).await;
+                            if let Some(proce) = &va.inject {
+                                let (stdout_stream, forwarder) = if va.reply == ReplyMode::Stdout && va.stream.is_some() {
+                                    let (ltx, lrx) = mpsc::unbounded_channel();
+                                    (Some(ltx), Some(forward_stdout(subscriptions.clone(), &uuid, lrx)))
+                                } else {
+                                    (None, None)
+                                };
+                                let exited = pctl.run_back_process_streaming(proce, *req, &uuid, stdout_stream).await;
+                                let jobs = if asyncjob { Some(jobs.clone()) } else { None };
+                                let exited = collect_exit(jobs, stats_tx, exit_to, permit, pctl.clone(), &uuid, exited);
+                                reply_on_exit(subscriptions.clone(), &uuid, exited, forwarder, &va, config.debug);
                             } else {
                                 warn!("not found");
                             }
-                            let _ = respond_to.send((rx,uuid));
+                            if asyncjob {
+                                let id = uuid.clone();
+                                tokio::spawn(async move {
+                                    if let Ok(response) = rx.await {
+                                        jobs.finish(&id, response).await;
+                                        if let (Some(hook), Some(callback_exit)) = (callback, callback_exit) {
+                                            let exit = callback_exit.await.ok();
+                                            if let Some(reply) = jobs.state(&id).await.and_then(|state| state.reply()) {
+                                                hook.deliver(&id, &reply, exit.as_ref()).await;
+                                            }
+                                        }
+                                    }
+                                });
+                                let (tx2, rx ) = oneshot::channel();
+                                let _ = tx2.send(FrontResponse::Accepted(uuid.clone()));
+                                let _ = respond_to.send((rx,uuid));
+                            } else {
+                                let _ = respond_to.send((rx,uuid));
+                            }
                         },
-                        None => {
-                            warn!("No executor associated");
-                            // so return something like code 500 to the caller.
+                        RouteMatch::MethodNotAllowed(allowed) => {
+                            warn!("Method {} not allowed for {}", req.method(), req.uri());
                             let (tx2, rx ) = oneshot::channel();
-                            let _ = tx2.send(FrontResponse::InternalError);
+                            let _ = tx2.send(FrontResponse::MethodNotAllowed(allowed));
+                            let _ = respond_to.send((rx,String::from("")));
+                        }
+                        RouteMatch::NotFound => {
+                            let (tx2, rx ) = oneshot::channel();
+                            let job_status = match req.uri().strip_prefix(JOBS_PATH) {
+                                Some(id) if req.method() == Method::GET => jobs.status(id).await,
+                                _ => None
+                            };
+                            if let Some(body) = job_status {
+                                let _ = tx2.send(FrontResponse::Output { code: 200, content_type: String::from("application/json"), headers: vec![], body: Bytes::from(body) });
+                                let _ = respond_to.send((rx,String::from("")));
+                                return;
+                            }
+                            warn!("No executor associated to {}", req.uri());
+                            let _ = tx2.send(FrontResponse::NotFound);
                             let _ = respond_to.send((rx,String::from("")));
                         }
                     };
@@ -122,9 +405,36 @@ impl RequestsVisorActor {
                 tokio::spawn(async move {
                     let mut subscrs = subscriptions.lock().await;
                     if let Some(m) = subscrs.remove(&req_id) {
-                        let Subscriber { request_id: _ , timeout: _, respond_to: tx } = m;
-                        let _ = tx.send(FrontResponse::BackMsg(response));
+                        let Subscriber { respond_to: tx, jsonreply, validateout, stream, stats, started, .. } = m;
+                        if let (ReplyTo::Stream(chunks), Some(format)) = (&tx, stream) {
+                            // the last chunk, the stream ends when chunks is dropped
+                            drop(subscrs);
+                            if !response.body.is_empty() {
+                                let _ = chunks.send(Ok(stream_data(&format, response.body))).await;
+                            }
+                            let _ = respond_to.send(true);
+                            return;
+                        }
+                        let response = if jsonreply {
+                            json_reply(response)
+                        } else {
+                            Ok(response)
+                        };
+                        let reply = match (response, validateout) {
+                            (Err(e), _) => {
+                                warn!("request {} reply is not valid JSON: {}", req_id, e);
+                                FrontResponse::BadGateway(serde_json::json!({"error": format!("invalid JSON reply: {}", e)}).to_string())
+                            }
+                            (Ok(response), Some(v)) => v.check(&req_id, response),
+                            (Ok(response), None) => FrontResponse::BackMsg(response)
+                        };
+                        drop(subscrs);
                         let _ = respond_to.send(true);
+                        let reply = match stats {
+                            Some(stats) => add_stats(reply, stats, started, &req_id),
+                            None => reply
+                        };
+                        tx.send(reply);
                     } else {
                         // !!!TODO:
                         // 1. something else replied 
@@ -135,8 +445,328 @@ impl RequestsVisorActor {
 
                 });
             }
+            ReqVisorMsg::PushChunk { req_id, chunk, respond_to } => {
+                let subscriptions = self.subscriptions.clone();
+                tokio::spawn(async move {
+                    let _ = respond_to.send(push_chunk(&subscriptions, &req_id, chunk).await);
+                });
+            }
+            ReqVisorMsg::Extend { req_id, by_ms, respond_to } => {
+                let subscriptions = self.subscriptions.clone();
+                let pctl = self.pctl.clone();
+                tokio::spawn(async move {
+                    let extended = {
+                        let mut subscrs = subscriptions.lock().await;
+                        match subscrs.get_mut(&req_id) {
+                            Some(m) => m.extend(by_ms),
+                            None => None
+                        }
+                    };
+                    if let Some(until) = extended {
+                        // the process is given the same time
+                        let by_ms = until.saturating_duration_since(Instant::now()).as_millis() as u64;
+                        pctl.extend(&req_id, by_ms).await;
+                    }
+                    let _ = respond_to.send(extended.is_some());
+                });
+            }
+        };
+    }
+}
+
+/// Send a chunk of a streamed reply; the first chunk starts the stream,
+/// with its status and headers.
+/// Returns false if req_id is not pending or its route does not stream
+async fn push_chunk(subscriptions: &Subscriptions, req_id: &str, chunk: ForHttpResponse) -> bool {
+    let (chunks, format) = {
+        let mut subscrs = subscriptions.lock().await;
+        let m = match subscrs.get_mut(req_id) {
+            Some(m) => m,
+            None => return false
+        };
+        let format = match &m.stream {
+            Some(f) => f.clone(),
+            None => {
+                warn!("request {} got a chunk, but its route does not stream", req_id);
+                return false;
+            }
+        };
+        if let ReplyTo::Front(_) = m.respond_to {
+            let (ctx, crx) = mpsc::channel(STREAM_BUFFER);
+            let mut headers = chunk.headers.clone();
+            let content_type = match format {
+                StreamFormat::Sse => Some(String::from("text/event-stream")),
+                StreamFormat::Chunked if chunk.content_type().is_none() => Some(m.contenttype.clone().unwrap_or(String::from(DEFAULT_STREAM_CONTENT_TYPE))),
+                StreamFormat::Chunked => None
+            };
+            if let Some(ct) = content_type {
+                headers.retain(|(k, _)| !k.eq_ignore_ascii_case("content-type"));
+                headers.push((String::from("content-type"), ct));
+            }
+            let code = u16::try_from(chunk.code).unwrap_or(200);
+            let front = std::mem::replace(&mut m.respond_to, ReplyTo::Stream(ctx));
+            front.send(FrontResponse::Stream { code, headers, chunks: crx });
+        }
+        match &m.respond_to {
+            ReplyTo::Stream(chunks) => (chunks.clone(), format),
+            ReplyTo::Front(_) => return false
+        }
+    };
+    // out of the lock: a slow client slows down the sender only
+    chunks.send(Ok(stream_data(&format, chunk.body))).await.is_ok()
+}
+
+fn stream_data(format: &StreamFormat, body: Bytes) -> Bytes {
+    match format {
+        StreamFormat::Chunked => body,
+        StreamFormat::Sse => sse_event(&body)
+    }
+}
+
+/// A `text/event-stream` event, a `data:` field for each line of the chunk
+fn sse_event(chunk: &[u8]) -> Bytes {
+    let text = String::from_utf8_lossy(chunk);
+    let mut event = String::new();
+    for line in text.strip_suffix('\n').unwrap_or(&text).split('\n') {
+        event.push_str("data: ");
+        event.push_str(line.strip_suffix('\r').unwrap_or(line));
+        event.push('\n');
+    }
+    event.push('\n');
+    Bytes::from(event)
+}
+
+/// Forward the process stdout lines as chunks of the streamed reply (`reply: stdout`)
+fn forward_stdout(subscriptions: Subscriptions, uuid: &str, mut lines: mpsc::UnboundedReceiver<Bytes>) -> tokio::task::JoinHandle<()> {
+    let uuid = uuid.to_string();
+    tokio::spawn(async move {
+        while let Some(line) = lines.recv().await {
+            let chunk = ForHttpResponse { code: 200, body: line, ..Default::default() };
+            if !push_chunk(&subscriptions, &uuid, chunk).await {
+                break;
+            }
+        }
+    })
+}
+
+/// Parse the reply body as JSON and re-serialize it, as `application/json` (`jsonreply`)
+fn json_reply(mut response: ForHttpResponse) -> Result<ForHttpResponse, serde_json::Error> {
+    let data: serde_json::Value = serde_json::from_slice(&response.body)?;
+    response.body = Bytes::from(data.to_string());
+    response.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("content-type"));
+    response.headers.push((String::from("content-type"), String::from("application/json")));
+    Ok(response)
+}
+
+/// Validate the request against the OpenAPI document, when `validatein` is set
+fn validate_in(config: &ServiceConf, va: &VerbAction, req: &RestMessage) -> Result<(), Violations> {
+    match &config.openapidoc {
+        Some(doc) if va.validatein => doc.validate_request(req),
+        _ => Ok(())
+    }
+}
+
+/// Wait for the subscriber deadline (it can be pushed back meanwhile), then,
+/// if the request is still pending, evict it and answer with 504 (FrontResponse::Timeout),
+/// or abort its started stream.
+/// The eviction is logged together with ProcessInfos, if available
+async fn evict_on_deadline(subscriptions: Subscriptions, pctl: ProcessController, uuid: &str, timeoutbody: String) {
+    if !subscriptions.lock().await.contains_key(uuid) {
+        return;
+    }
+    let uuid = uuid.to_string();
+    tokio::spawn(async move {
+        let m = loop {
+            let deadline = {
+                let mut subscrs = subscriptions.lock().await;
+                match subscrs.get(&uuid) {
+                    Some(m) if m.deadline <= Instant::now() => break subscrs.remove(&uuid),
+                    Some(m) => m.deadline,
+                    None => break None
+                }
+            };
+            tokio::time::sleep_until(deadline).await;
         };
+        if let Some(m) = m {
+            let elapsed = m.started.elapsed().as_millis();
+            m.respond_to.fail(FrontResponse::Timeout(timeoutbody), "deadline expired").await;
+            let (tx, mut rx) = mpsc::channel(1);
+            pctl.get_infos(&uuid, tx).await;
+            match rx.recv().await {
+                Some(Some(pi)) => warn!("request {} evicted after {}ms, process: {:?}", uuid, elapsed, pi),
+                _ => warn!("request {} evicted after {}ms, no process infos", uuid, elapsed)
+            }
+        }
+    });
+}
+
+/// The webhook the result is delivered to: the `X-Urocket-Callback` header
+/// (if the route allows it) or the route `callback`
+fn callback_webhook(config: &ServiceConf, va: &VerbAction, req: &RestMessage) -> Result<Option<Webhook>, String> {
+    let url = match req.header(CALLBACK_HEADER) {
+        Some(url) if va.clientcallback => url,
+        Some(_) => return Err(String::from("X-Urocket-Callback is not allowed for this route")),
+        None => match &va.callback {
+            Some(url) => url.clone(),
+            None => return Ok(None)
+        }
+    };
+    let hook = Webhook::new(
+        &url,
+        config.webhooksecret.clone(),
+        config.webhookretries.unwrap_or(DEFAULT_RETRIES),
+        config.webhookbackoff.unwrap_or(DEFAULT_BACKOFF),
+        config.webhooktimeout.unwrap_or(DEFAULT_DELIVERY_TIMEOUT)
+    )?;
+    Ok(Some(hook))
+}
+
+/// When the process exits release its admission permit and get its ProcessInfos,
+/// with the queue wait: they are stored in the job (`async`) and published for the
+/// stats header (`stats`). The returned receiver, and `exit_to` (the callback), get
+/// the exit after that
+fn collect_exit(jobs: Option<JobStore>, stats: Option<watch::Sender<Option<serde_json::Value>>>, exit_to: Option<oneshot::Sender<ProcessExit>>, permit: Permit, pctl: ProcessController, uuid: &str, exited: Receiver<ProcessExit>) -> Receiver<ProcessExit> {
+    let (tx, rx) = oneshot::channel();
+    let uuid = uuid.to_string();
+    tokio::spawn(async move {
+        let exited = exited.await;
+        let (waited_ms, queue_depth) = (permit.waited_ms, permit.queue_depth);
+        // the next queued request can spawn its process
+        drop(permit);
+        if let Ok(exit) = exited {
+            if jobs.is_none() && stats.is_none() {
+                let _ = tx.send(exit);
+                return;
+            }
+            let (itx, mut irx) = mpsc::channel(1);
+            pctl.get_infos(&uuid, itx).await;
+            let process = irx.recv().await.flatten().map(|pi| {
+                let mut infos = pi.to_json();
+                infos["queue_ms"] = serde_json::Value::from(waited_ms);
+                infos["queue_depth"] = serde_json::Value::from(queue_depth);
+                infos
+            });
+            if let Some(stats) = stats {
+                stats.send_replace(Some(process.clone().unwrap_or(serde_json::json!({"error": "no process infos"}))));
+            }
+            if let Some(jobs) = jobs {
+                jobs.set_exit(&uuid, exit.clone(), process).await;
+            }
+            if let Some(exit_to) = exit_to {
+                let _ = exit_to.send(exit.clone());
+            }
+            let _ = tx.send(exit);
+        }
+    });
+    rx
+}
+
+/// Add the `urocket-stats` header to the reply, without waiting: when the process
+/// is still running the header is `{"running":true,...}` and its stats are logged
+/// when it exits
+fn add_stats(reply: FrontResponse, mut stats: StatsSlot, started: Instant, req_id: &str) -> FrontResponse {
+    let exited = stats.borrow_and_update().clone();
+    let value = match exited {
+        Some(value) => value,
+        None => {
+            let req_id = req_id.to_string();
+            tokio::spawn(async move {
+                if let Ok(value) = stats.wait_for(|s| s.is_some()).await {
+                    info!("request {} process stats: {}", req_id, value.clone().unwrap_or_default());
+                }
+            });
+            serde_json::json!({"running": true, "wall_ms": started.elapsed().as_millis() as u64})
+        }
+    };
+    let header = (String::from(STATS_HEADER), value.to_string());
+    match reply {
+        FrontResponse::BackMsg(mut response) => {
+            response.headers.push(header);
+            FrontResponse::BackMsg(response)
+        }
+        FrontResponse::Output { code, content_type, mut headers, body } => {
+            headers.push(header);
+            FrontResponse::Output { code, content_type, headers, body }
+        }
+        other => other
+    }
+}
+
+/// Wait for the process to end, if the request is still pending:
+///  - a started stream is ended
+///  - with `reply: stdout` it is fulfilled with the process stdout, if not truncated
+///  - otherwise it is failed with 502 (FrontResponse::BadGateway)
+fn reply_on_exit(subscriptions: Subscriptions, uuid: &str, exited: Receiver<ProcessExit>, forwarder: Option<tokio::task::JoinHandle<()>>, va: &VerbAction, debug: bool) {
+    let uuid = uuid.to_string();
+    let va = va.clone();
+    tokio::spawn(async move {
+        let exit = exited.await.unwrap_or_else(|_| ProcessExit {
+            error: Some(String::from("process controller lost the process")),
+            ..Default::default()
+        });
+        if let Some(forwarder) = forwarder {
+            // the last stdout lines are still on their way
+            let _ = forwarder.await;
+        }
+        let m = subscriptions.lock().await.remove(&uuid);
+        if let Some(m) = m {
+            if let ReplyTo::Stream(_) = m.respond_to {
+                if exit.was_killed {
+                    warn!("request {} stream aborted, the process was killed", uuid);
+                    m.respond_to.fail(FrontResponse::InternalError, "process killed").await;
+                } else {
+                    info!("request {} stream ended, the process exited: {:?}", uuid, exit.code);
+                }
+                return;
+            }
+            match (&va.reply, exit.code) {
+                (ReplyMode::Stdout, Some(code)) if exit.error.is_none() && !exit.truncated => {
+                    let response = FrontResponse::Output {
+                        code: va.exit_status(code),
+                        content_type: va.contenttype.clone().unwrap_or(String::from("application/json")),
+                        headers: vec![],
+                        body: exit.stdout_raw,
+                    };
+                    let response = match m.stats {
+                        Some(stats) => add_stats(response, stats, m.started, &uuid),
+                        None => response
+                    };
+                    m.respond_to.send(response);
+                }
+                _ => {
+                    warn!("request {} failed, the process exited without reply: {:?}", uuid, exit);
+                    m.respond_to.send(FrontResponse::BadGateway(exit_error_body(&exit, debug)));
+                }
+            }
+        }
+    });
+}
+
+/// The JSON body reporting the process exit status, with the
+/// beginning of stderr in debug mode
+fn exit_error_body(exit: &ProcessExit, debug: bool) -> String {
+    let error = match (&exit.error, &exit.limit) {
+        (Some(error), _) => error.clone(),
+        (None, Some(limit)) => format!("process failed on the {} limit", limit),
+        (None, None) if exit.truncated => String::from("process output truncated at maxoutput"),
+        (None, None) => String::from("process exited without reply"),
+    };
+    let mut body = serde_json::json!({
+        "error": error,
+        "exitcode": exit.code,
+        "signal": exit.signal,
+    });
+    if let Some(limit) = &exit.limit {
+        body["limit"] = serde_json::Value::String(limit.clone());
     }
+    if debug {
+        let mut end = exit.stderr.len().min(STDERR_REPORT_LEN);
+        while !exit.stderr.is_char_boundary(end) {
+            end -= 1;
+        }
+        body["stderr"] = serde_json::Value::String(exit.stderr[..end].to_string());
+    }
+    body.to_string()
 }
 
 actor_handler!({pctl: &ProcessController, conf: &ServiceConf} => RequestsVisorActor, RequestsVisor, ReqVisorMsg);
@@ -147,7 +777,7 @@ impl RequestsVisor {
         //let arbiter = self.arbiter.clone();
         let (tx, rx) = tokio::sync::oneshot::channel();
         let msg = ReqVisorMsg::RegisterPending {
-            req,
+            req: Box::new(req),
             respond_to: tx,
         };
         let s = self.clone();
@@ -174,12 +804,47 @@ impl RequestsVisor {
         });
         rx
     }
+
+    /// Push back the request deadline, and the process kill time, by `by_ms`
+    /// (default the route deadline) up to the route `maxdeadline`: the receiver
+    /// gets false if req_id is not pending or the route has no `maxdeadline`
+    pub fn push_extend(&self, req_id: &str, by_ms: Option<u64>) -> tokio::sync::oneshot::Receiver<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let msg = ReqVisorMsg::Extend {
+            req_id: req_id.to_string(),
+            by_ms,
+            respond_to: tx
+        };
+        let s = self.clone();
+        tokio::spawn(async move {
+            let _ = toktor_send!(s, msg).await;
+        });
+        rx
+    }
+
+    /// Send a chunk of a streamed reply, the receiver gets true
+    /// if req_id is pending on a streaming route
+    pub fn push_chunk(&self, req_id: &str, chunk: ForHttpResponse) -> tokio::sync::oneshot::Receiver<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let msg = ReqVisorMsg::PushChunk {
+            req_id: req_id.to_string(),
+            chunk,
+            respond_to: tx
+        };
+        let s = self.clone();
+        tokio::spawn(async move {
+            let _ = toktor_send!(s, msg).await;
+        });
+        rx
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use crate::toktor_new;
+    use crate::procenv::ProcEnv;
+    use crate::serviceconf::PathVerb;
     use super::*;
 
     #[tokio::test]
@@ -190,7 +855,7 @@ mod tests {
         let req = RestMessage::new("get", "/myurl", "");
         let rx = visor.wait_for(req);
         let (x, uuid) =  rx.await.unwrap();
-        let response = ForHttpResponse { code: 1, data: serde_json::Value::String(String::from("helpme please")) };
+        let response = ForHttpResponse { code: 1, body: Bytes::from("helpme please"), ..Default::default() };
         let rx = visor.push_fulfill(&uuid, response);
         match rx.await {
             Ok(d) => {
@@ -214,6 +879,18 @@ mod tests {
                         eprintln!("Internal error");
                         //assert_eq!(false, true);
                     }
+                    FrontResponse::Output { code, body, .. } => {
+                        println!("VISOR caller got the process output {}: {:?}", code, body);
+                    }
+                    FrontResponse::NotFound | FrontResponse::MethodNotAllowed(_) => {
+                        eprintln!("No route");
+                    }
+                    FrontResponse::Stream { .. } | FrontResponse::Accepted(_) => {
+                        eprintln!("Unexpected stream or job");
+                    }
+                    FrontResponse::Timeout(_) | FrontResponse::BadGateway(_) | FrontResponse::Unavailable { .. } => {
+                        eprintln!("No reply");
+                    }
                 }
             },
             Err(e) => {
@@ -222,4 +899,492 @@ mod tests {
         }
         tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_process_exit() {
+        let mut conf = ServiceConf { debug: true, ..Default::default() };
+        let va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo broken >&2; exit 3"], "")),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/broken"), PathVerb { post: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let req = RestMessage::new("post", "/broken", "");
+        let (x, _) = visor.wait_for(req).await.unwrap();
+        match x.await.unwrap() {
+            FrontResponse::BadGateway(body) => {
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                assert_eq!(body["exitcode"], 3);
+                assert_eq!(body["stderr"], "broken");
+            }
+            _ => panic!("the request should fail")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_stdout_reply() {
+        let mut conf = ServiceConf::default();
+        let mut va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo '{{jsonpayload}}'; exit $0", "{{path.code}}"], "")),
+            reply: ReplyMode::Stdout,
+            ..Default::default()
+        };
+        va.exitcodes.insert(4, 404);
+        conf.paths.insert(String::from("/exit/{code}"), PathVerb { post: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        for (code, status) in [(0, 200), (4, 404), (1, 500)] {
+            let req = RestMessage::new("post", &format!("/exit/{}", code), "{\"hello\":true}");
+            let (x, _) = visor.wait_for(req).await.unwrap();
+            match x.await.unwrap() {
+                FrontResponse::Output { code, content_type, body, .. } => {
+                    assert_eq!(code, status);
+                    assert_eq!(content_type, "application/json");
+                    assert_eq!(body, "{\"hello\":true}\n");
+                }
+                _ => panic!("the request should be fulfilled by stdout")
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_binary_stdout() {
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["printf", "\\211PNG\\r\\n\\032\\n"], "")),
+            reply: ReplyMode::Stdout,
+            contenttype: Some(String::from("image/png")),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/image"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, _) = visor.wait_for(RestMessage::new("get", "/image", "")).await.unwrap();
+        match x.await.unwrap() {
+            FrontResponse::Output { code, content_type, body, .. } => {
+                assert_eq!((code, content_type.as_str()), (200, "image/png"));
+                // the bytes as written: not UTF-8, the final newline kept
+                assert_eq!(body, Bytes::from_static(b"\x89PNG\r\n\x1a\n"));
+            }
+            _ => panic!("the request should be fulfilled by stdout")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_truncated_stdout() {
+        let mut conf = ServiceConf::default();
+        let mut proce = ProcEnv::new_v("", vec![], &["printf", "0123456789"], "");
+        proce.maxoutput = Some(4);
+        let va = VerbAction { inject: Some(proce), reply: ReplyMode::Stdout, ..Default::default() };
+        conf.paths.insert(String::from("/digits"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, _) = visor.wait_for(RestMessage::new("get", "/digits", "")).await.unwrap();
+        match x.await.unwrap() {
+            // a partial body must not look like a complete one
+            FrontResponse::BadGateway(body) => {
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                assert_eq!(body["error"], "process output truncated at maxoutput");
+                assert_eq!(body["exitcode"], 0);
+            }
+            _ => panic!("the truncated output should fail")
+        }
+    }
+
+    #[tokio::test]
+    async fn visor_validatein() {
+        let openapidoc = Some(Arc::new(OpenApi::load("examples/pets-oas.yaml").await.unwrap()));
+        let mut conf = ServiceConf { openapidoc, ..Default::default() };
+        let va = VerbAction {
+            inject: Some(ProcEnv::new("", vec![], "true", "")),
+            validatein: true,
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/pet/{petId}"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let req = RestMessage::new("get", "/pet/doggie", "");
+        let (x, uuid) = visor.wait_for(req).await.unwrap();
+        assert_eq!(uuid, "");
+        match x.await.unwrap() {
+            FrontResponse::Output { code, body, .. } => {
+                assert_eq!(code, 400);
+                assert!(String::from_utf8_lossy(&body).contains("petId"));
+            }
+            _ => panic!("the request should be rejected")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_validateout() {
+        let openapidoc = Some(Arc::new(OpenApi::load("examples/pets-oas.yaml").await.unwrap()));
+        let mut conf = ServiceConf { openapidoc, ..Default::default() };
+        for (route, policy) in [("/pet/{petId}", ValidateOutPolicy::Reject), ("/store/order/{orderId}", ValidateOutPolicy::Record)] {
+            let va = VerbAction {
+                inject: Some(ProcEnv::new("", vec![], "sleep 1", "")),
+                validateout: true,
+                validateoutpolicy: policy,
+                ..Default::default()
+            };
+            conf.paths.insert(String::from(route), PathVerb { get: Some(va), ..Default::default() });
+        }
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let invalid = Bytes::from(r#"{"id": "twelve"}"#);
+        for (uri, rejected) in [("/pet/12", true), ("/store/order/12", false)] {
+            let (x, uuid) = visor.wait_for(RestMessage::new("get", uri, "")).await.unwrap();
+            let response = ForHttpResponse { code: 200, body: invalid.clone(), ..Default::default() };
+            assert!(visor.push_fulfill(&uuid, response).await.unwrap());
+            match x.await.unwrap() {
+                FrontResponse::BadGateway(body) => assert!(rejected && body.contains("/id")),
+                FrontResponse::BackMsg(mb) => assert!(!rejected && mb.body == invalid),
+                _ => panic!("unexpected response")
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_jsonreply() {
+        let mut conf = ServiceConf::default();
+        for (route, jsonreply) in [("/csv", false), ("/json", true)] {
+            let va = VerbAction {
+                inject: Some(ProcEnv::new("", vec![], "sleep 1", "")),
+                jsonreply,
+                ..Default::default()
+            };
+            conf.paths.insert(String::from(route), PathVerb { get: Some(va), ..Default::default() });
+        }
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let csv = ForHttpResponse {
+            code: 200,
+            body: Bytes::from("id,name\n12,doggie\n"),
+            headers: vec![(String::from("content-type"), String::from("text/csv"))],
+        };
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/csv", "")).await.unwrap();
+        assert!(visor.push_fulfill(&uuid, csv.clone()).await.unwrap());
+        match x.await.unwrap() {
+            FrontResponse::BackMsg(mb) => assert_eq!(mb, csv),
+            _ => panic!("unexpected response")
+        }
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/json", "")).await.unwrap();
+        let json = ForHttpResponse { code: 201, body: Bytes::from("{ \"id\" : 12 }"), ..Default::default() };
+        assert!(visor.push_fulfill(&uuid, json).await.unwrap());
+        match x.await.unwrap() {
+            FrontResponse::BackMsg(mb) => {
+                assert_eq!(mb.body, Bytes::from("{\"id\":12}"));
+                assert_eq!(mb.content_type(), Some("application/json"));
+            }
+            _ => panic!("unexpected response")
+        }
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/json", "")).await.unwrap();
+        assert!(visor.push_fulfill(&uuid, csv).await.unwrap());
+        assert!(matches!(x.await.unwrap(), FrontResponse::BadGateway(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_stream_chunks() {
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new("", vec![], "sleep 1", "")),
+            stream: Some(StreamFormat::Sse),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/events"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/events", "")).await.unwrap();
+        let chunk = ForHttpResponse { code: 200, body: Bytes::from("one\ntwo\n"), ..Default::default() };
+        assert!(visor.push_chunk(&uuid, chunk).await.unwrap());
+        let mut chunks = match x.await.unwrap() {
+            FrontResponse::Stream { code, headers, chunks } => {
+                assert_eq!(code, 200);
+                assert!(headers.contains(&(String::from("content-type"), String::from("text/event-stream"))));
+                chunks
+            }
+            _ => panic!("the reply should stream")
+        };
+        assert_eq!(chunks.recv().await.unwrap().unwrap(), Bytes::from("data: one\ndata: two\n\n"));
+        let last = ForHttpResponse { code: 200, body: Bytes::from("three"), ..Default::default() };
+        assert!(visor.push_fulfill(&uuid, last).await.unwrap());
+        assert_eq!(chunks.recv().await.unwrap().unwrap(), Bytes::from("data: three\n\n"));
+        assert!(chunks.recv().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_stream_stdout() {
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo first; sleep 0.2; echo second"], "")),
+            reply: ReplyMode::Stdout,
+            stream: Some(StreamFormat::Chunked),
+            contenttype: Some(String::from("text/plain")),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/lines"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, _) = visor.wait_for(RestMessage::new("get", "/lines", "")).await.unwrap();
+        let mut chunks = match x.await.unwrap() {
+            FrontResponse::Stream { headers, chunks, .. } => {
+                assert!(headers.contains(&(String::from("content-type"), String::from("text/plain"))));
+                chunks
+            }
+            _ => panic!("the reply should stream")
+        };
+        assert_eq!(chunks.recv().await.unwrap().unwrap(), Bytes::from("first\n"));
+        assert_eq!(chunks.recv().await.unwrap().unwrap(), Bytes::from("second\n"));
+        assert!(chunks.recv().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_stream_deadline() {
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "echo first; sleep 1; echo late"], "")),
+            reply: ReplyMode::Stdout,
+            stream: Some(StreamFormat::Chunked),
+            deadline: Some(300),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/lines"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, _) = visor.wait_for(RestMessage::new("get", "/lines", "")).await.unwrap();
+        let mut chunks = match x.await.unwrap() {
+            FrontResponse::Stream { chunks, .. } => chunks,
+            _ => panic!("the reply should stream")
+        };
+        assert_eq!(chunks.recv().await.unwrap().unwrap(), Bytes::from("first\n"));
+        // the deadline expired: the stream is aborted, not ended
+        assert!(chunks.recv().await.unwrap().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn visor_extend() {
+        let mut conf = ServiceConf::default();
+        let mut proce = ProcEnv::new("", vec![], "sleep 3", "");
+        proce.timeout = Some(300);
+        for (route, maxdeadline) in [("/slow", Some(1500)), ("/fixed", None)] {
+            let va = VerbAction {
+                inject: Some(proce.clone()),
+                deadline: Some(300),
+                maxdeadline,
+                ..Default::default()
+            };
+            conf.paths.insert(String::from(route), PathVerb { get: Some(va), ..Default::default() });
+        }
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/fixed", "")).await.unwrap();
+        assert!(!visor.push_extend(&uuid, None).await.unwrap());
+        // the kill time and the deadline are the same: 504 or 502
+        assert!(matches!(x.await.unwrap(), FrontResponse::Timeout(_) | FrontResponse::BadGateway(_)));
+        // the deadline and the process kill time are pushed back to 1500ms, not over
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/slow", "")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(visor.push_extend(&uuid, Some(10000)).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let response = ForHttpResponse { code: 200, body: Bytes::from("late"), ..Default::default() };
+        assert!(visor.push_fulfill(&uuid, response).await.unwrap());
+        assert!(matches!(x.await.unwrap(), FrontResponse::BackMsg(_)));
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/slow", "")).await.unwrap();
+        assert!(visor.push_extend(&uuid, Some(10000)).await.unwrap());
+        assert!(matches!(x.await.unwrap(), FrontResponse::Timeout(_) | FrontResponse::BadGateway(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn visor_async_job() {
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new("", vec![], "sleep 0.3", "")),
+            asyncjob: true,
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/import"), PathVerb { post: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let job_status = |id: &str| {
+            let (visor, uri) = (visor.clone(), format!("/jobs/{}", id));
+            async move {
+                match visor.wait_for(RestMessage::new("get", &uri, "")).await.unwrap().0.await.unwrap() {
+                    FrontResponse::Output { body, .. } => Some(serde_json::from_slice::<serde_json::Value>(&body).unwrap()),
+                    _ => None
+                }
+            }
+        };
+        let (x, uuid) = visor.wait_for(RestMessage::new("post", "/import", "")).await.unwrap();
+        match x.await.unwrap() {
+            FrontResponse::Accepted(id) => assert_eq!(id, uuid),
+            _ => panic!("the request should be accepted")
+        }
+        assert_eq!(job_status(&uuid).await.unwrap()["status"], "pending");
+        let response = ForHttpResponse { code: 201, body: Bytes::from("imported"), ..Default::default() };
+        assert!(visor.push_fulfill(&uuid, response).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = job_status(&uuid).await.unwrap();
+        assert_eq!(status["status"], "done");
+        assert_eq!(status["code"], 201);
+        assert_eq!(status["body"], "imported");
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(job_status(&uuid).await.unwrap()["exit"]["code"], 0);
+        assert!(job_status("unknown").await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn visor_callback() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "sleep 0.3; exit 3"], "")),
+            callback: Some(format!("http://{}/hook", listener.local_addr().unwrap())),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/import"), PathVerb { post: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (x, uuid) = visor.wait_for(RestMessage::new("post", "/import", "")).await.unwrap();
+        assert!(matches!(x.await.unwrap(), FrontResponse::Accepted(_)));
+        let response = ForHttpResponse { code: 201, body: Bytes::from("imported"), ..Default::default() };
+        assert!(visor.push_fulfill(&uuid, response).await.unwrap());
+        // delivered once the process exited, with its exit code
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(3), listener.accept()).await.unwrap().unwrap();
+        let mut request = Vec::new();
+        let mut chunk = [0; 1024];
+        while !request.ends_with(b"imported") {
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&chunk[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await.unwrap();
+        let request = String::from_utf8_lossy(&request).to_lowercase();
+        assert!(request.contains(&format!("x-urocket-request-id: {}", uuid)));
+        assert!(request.contains("x-urocket-status: 201"));
+        assert!(request.contains("x-urocket-exit-code: 3"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn visor_stats() {
+        let mut conf = ServiceConf::default();
+        for (route, reply) in [("/socket", ReplyMode::Socket), ("/stdout", ReplyMode::Stdout)] {
+            let va = VerbAction {
+                inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "sleep 0.2; echo done"], "")),
+                reply,
+                stats: true,
+                ..Default::default()
+            };
+            conf.paths.insert(String::from(route), PathVerb { get: Some(va), ..Default::default() });
+        }
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let stats_of = |headers: &[(String, String)]| {
+            let (_, stats) = headers.iter().find(|(k, _)| k == STATS_HEADER).unwrap();
+            serde_json::from_str::<serde_json::Value>(stats).unwrap()
+        };
+        // the process replies before exiting: the reply is not held for the stats
+        let (x, uuid) = visor.wait_for(RestMessage::new("get", "/socket", "")).await.unwrap();
+        let response = ForHttpResponse { code: 200, body: Bytes::from("ok"), ..Default::default() };
+        let sent = Instant::now();
+        assert!(visor.push_fulfill(&uuid, response).await.unwrap());
+        let stats = match x.await.unwrap() {
+            FrontResponse::BackMsg(mb) => stats_of(&mb.headers),
+            _ => panic!("unexpected response")
+        };
+        assert!(sent.elapsed() < Duration::from_millis(150));
+        assert_eq!(stats["running"], true);
+        let (x, _) = visor.wait_for(RestMessage::new("get", "/stdout", "")).await.unwrap();
+        let stats = match x.await.unwrap() {
+            FrontResponse::Output { headers, .. } => stats_of(&headers),
+            _ => panic!("unexpected response")
+        };
+        assert!(stats["wall_ms"].as_u64().unwrap() >= 200);
+        assert_eq!(stats["stdout_len"], 5);
+        assert!(stats["maxrss"].is_u64());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn visor_concurrency() {
+        let mut conf = ServiceConf { queuesize: Some(1), queuetimeout: Some(3000), ..Default::default() };
+        let va = VerbAction {
+            inject: Some(ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "sleep 0.3; echo done"], "")),
+            reply: ReplyMode::Stdout,
+            maxconcurrent: Some(1),
+            stats: true,
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/slow"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let (first, _) = visor.wait_for(RestMessage::new("get", "/slow", "")).await.unwrap();
+        let queued = {
+            let visor = visor.clone();
+            tokio::spawn(async move { visor.wait_for(RestMessage::new("get", "/slow", "")).await.unwrap().0.await.unwrap() })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (full, _) = visor.wait_for(RestMessage::new("get", "/slow", "")).await.unwrap();
+        match full.await.unwrap() {
+            FrontResponse::Unavailable { retry_after, body } => {
+                assert_eq!(retry_after, 3);
+                assert!(body.contains("queue is full"));
+            }
+            _ => panic!("unexpected response")
+        }
+        assert!(matches!(first.await.unwrap(), FrontResponse::Output { .. }));
+        match queued.await.unwrap() {
+            FrontResponse::Output { headers, .. } => {
+                let (_, stats) = headers.iter().find(|(k, _)| k == STATS_HEADER).unwrap();
+                let stats: serde_json::Value = serde_json::from_str(stats).unwrap();
+                assert!(stats["queue_ms"].as_u64().unwrap() >= 100);
+                assert_eq!(stats["queue_depth"], 0);
+            }
+            _ => panic!("unexpected response")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_pool() {
+        let mut conf = ServiceConf::default();
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", "while read line; do echo '{\"exitcode\": 4, \"body\": {\"pool\": true}}'; done"], "");
+        proce.pool = Some(crate::procenv::PoolConf { workers: 2, ..Default::default() });
+        let va = VerbAction {
+            inject: Some(proce),
+            reply: ReplyMode::Stdout,
+            exitcodes: HashMap::from([(4, 404)]),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/pooled"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        for _ in 0..3 {
+            let (x, _) = visor.wait_for(RestMessage::new("get", "/pooled", "")).await.unwrap();
+            match x.await.unwrap() {
+                FrontResponse::Output { code, body, .. } => assert_eq!((code, body), (404, Bytes::from("{\"pool\":true}"))),
+                _ => panic!("unexpected response")
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn visor_deadline() {
+        let mut conf = ServiceConf::default();
+        let va = VerbAction {
+            inject: Some(ProcEnv::new("", vec![], "sleep 1", "")),
+            deadline: Some(200),
+            timeoutbody: Some(String::from("too slow")),
+            ..Default::default()
+        };
+        conf.paths.insert(String::from("/slow"), PathVerb { get: Some(va), ..Default::default() });
+        let pctl = toktor_new!(ProcessController);
+        let visor = toktor_new!(RequestsVisor, &pctl, &conf);
+        let req = RestMessage::new("get", "/slow", "");
+        let (x, uuid) = visor.wait_for(req).await.unwrap();
+        match x.await.unwrap() {
+            FrontResponse::Timeout(body) => assert_eq!(body, "too slow"),
+            _ => panic!("the request should time out")
+        }
+        let response = ForHttpResponse { code: 200, body: Bytes::from("true"), ..Default::default() };
+        assert!(!visor.push_fulfill(&uuid, response).await.unwrap());
+    }
 }
@@ -1,5 +1,9 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
 use bytes::Bytes;
-use hyper::Method;
+use hyper::{HeaderMap, Method};
 use http_body_util::BodyExt;
 use hyper::body::Incoming as IncomingBody;
 
@@ -8,7 +12,12 @@ use hyper::body::Incoming as IncomingBody;
 pub struct RestMessage {
     method: Method,
     uri: String,
-    data: String,
+    data: Bytes,
+    route: String,
+    path_params: HashMap<String,String>,
+    query: String,
+    headers: HeaderMap,
+    remote: Option<SocketAddr>,
 }
 impl RestMessage {
     pub fn new(m:&str, u:&str, d:&str) ->Self {
@@ -16,21 +25,17 @@ impl RestMessage {
             Ok(m) => m,
             Err(_) => Method::GET
         };
-        Self {method: m, uri: u.to_string(), data: d.to_string()}
+        Self {method: m, uri: u.to_string(), data: Bytes::from(d.to_string()), ..Default::default()}
     }
-    /// Create a new RestMessage from the Request payload
-    pub async fn parse_incoming(req: hyper::Request<IncomingBody>) -> Self {
+    /// Create a new RestMessage from the Request payload,
+    /// `remote` is the client address
+    pub async fn parse_incoming(req: hyper::Request<IncomingBody>, remote: SocketAddr) -> Self {
         let method = req.method().clone();
         let uri = req.uri().path().to_string();
-        let bites: Bytes = req.collect().await.unwrap().to_bytes();
-        let str = Vec::<u8>::from(bites.as_ref());
-        let body = match std::str::from_utf8(&str) {
-            Ok(s) => s,
-            Err(e) => {eprintln!("err{}",e); ""}
-        };
-
-        let data = String::from(body);
-        Self{ method,uri , data}
+        let query = req.uri().query().unwrap_or("").to_string();
+        let headers = req.headers().clone();
+        let data: Bytes = req.collect().await.unwrap().to_bytes();
+        Self{ method,uri , data, query, headers, remote: Some(remote), ..Default::default()}
     }
     pub fn method(&self) -> &Method {
         &self.method
@@ -38,7 +43,156 @@ impl RestMessage {
     pub fn uri(&self) -> &str {
         &self.uri
     }
-    pub fn body(&self) -> &str {
+    /// The request body, as received
+    pub fn body(&self) -> &Bytes {
         &self.data
     }
-}
\ No newline at end of file
+    /// The request body as text, invalid UTF-8 sequences are replaced
+    pub fn body_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.data)
+    }
+    pub fn set_body(&mut self, data: Bytes) {
+        self.data = data;
+    }
+    /// Set the configured path (template) matching this request,
+    /// and the parameters captured by it
+    pub fn set_route(&mut self, route: &str, params: HashMap<String,String>) {
+        self.route = route.to_string();
+        self.path_params = params;
+    }
+    /// The configured path matching this request, i.e. `/pet/{petId}`
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+    pub fn path_params(&self) -> &HashMap<String,String> {
+        &self.path_params
+    }
+    /// The raw query string, without `?`
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+    /// The decoded query parameters, in order
+    pub fn query_params(&self) -> Vec<(String,String)> {
+        self.query.split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let (k, v) = x.split_once('=').unwrap_or((x, ""));
+            (percent_decode(&k.replace('+', " ")), percent_decode(&v.replace('+', " ")))
+        })
+        .collect()
+    }
+    pub fn set_query(&mut self, query: &str) {
+        self.query = query.to_string();
+    }
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+    /// The value of the header, multiple values are joined by ", "
+    pub fn header(&self, name: &str) -> Option<String> {
+        let values: Vec<String> = self.headers.get_all(name).iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+    /// The client address
+    pub fn remote(&self) -> Option<&SocketAddr> {
+        self.remote.as_ref()
+    }
+
+    /// The placeholders available to ProcEnv `cmd` and `env`:
+    ///  - `jsonpayload`: the request body as text
+    ///  - `path.<name>`: the path template parameters
+    ///  - `query.<name>`: the query parameters (the first one, if repeated)
+    ///  - `header.<name>`: the request headers, lowercase name
+    ///
+    /// The values come from the client as they are (decoded, not quoted): they are
+    /// safe as whole argv elements only, never inside a shell script
+    pub fn placeholders(&self) -> HashMap<String,String> {
+        let mut placeholders = HashMap::new();
+        placeholders.insert("jsonpayload".to_string(), self.body_text().to_string());
+        for (name, val) in self.path_params() {
+            placeholders.insert(format!("path.{}", name), val.to_string());
+        }
+        for (name, val) in self.query_params() {
+            placeholders.entry(format!("query.{}", name)).or_insert(val);
+        }
+        for name in self.headers.keys().filter(|name| *name != "proxy") {
+            if let Some(val) = self.header(name.as_str()) {
+                placeholders.insert(format!("header.{}", name.as_str()), val);
+            }
+        }
+        placeholders
+    }
+
+    /// The environment variables describing the request, CGI alike:
+    /// `QUERY_STRING`, `REMOTE_ADDR`, `REMOTE_PORT` and `HTTP_<NAME>`
+    /// for each header (uppercase name, `-` replaced by `_`). The `Proxy`
+    /// header is skipped: as `HTTP_PROXY` it would set the proxy of the
+    /// process HTTP clients (httpoxy)
+    pub fn request_env(&self) -> Vec<(String,String)> {
+        let mut env = vec![("QUERY_STRING".to_string(), self.query.clone())];
+        if let Some(remote) = self.remote {
+            env.push(("REMOTE_ADDR".to_string(), remote.ip().to_string()));
+            env.push(("REMOTE_PORT".to_string(), remote.port().to_string()));
+        }
+        for name in self.headers.keys().filter(|name| *name != "proxy") {
+            if let Some(val) = self.header(name.as_str()) {
+                let var = format!("HTTP_{}", name.as_str().to_uppercase().replace('-', "_"));
+                env.push((var, val));
+            }
+        }
+        env
+    }
+}
+
+/// Decode `%XX` sequences, invalid sequences are kept as they are
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len()
+            && bytes[i+1].is_ascii_hexdigit() && bytes[i+2].is_ascii_hexdigit() {
+            let hex = std::str::from_utf8(&bytes[i+1..i+3]).unwrap();
+            out.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_placeholders() {
+        let mut rm = RestMessage::new("get", "/pets", "{}");
+        rm.set_query("page=2&name=big+cat%21&page=3&flag");
+        rm.headers_mut().insert("x-tenant", "acme".parse().unwrap());
+        rm.headers_mut().insert("proxy", "http://evil.example".parse().unwrap());
+        rm.headers_mut().append("accept", "text/csv".parse().unwrap());
+        rm.headers_mut().append("accept", "text/plain".parse().unwrap());
+        let placeholders = rm.placeholders();
+        assert_eq!(placeholders.get("jsonpayload").unwrap(), "{}");
+        assert_eq!(placeholders.get("query.page").unwrap(), "2");
+        assert_eq!(placeholders.get("query.name").unwrap(), "big cat!");
+        assert_eq!(placeholders.get("query.flag").unwrap(), "");
+        assert_eq!(placeholders.get("header.x-tenant").unwrap(), "acme");
+        assert_eq!(placeholders.get("header.accept").unwrap(), "text/csv, text/plain");
+        let env = rm.request_env();
+        assert!(env.contains(&("QUERY_STRING".to_string(), "page=2&name=big+cat%21&page=3&flag".to_string())));
+        assert!(env.contains(&("HTTP_X_TENANT".to_string(), "acme".to_string())));
+        assert!(!env.iter().any(|(var, _)| var == "HTTP_PROXY"));
+    }
+}
@@ -1,4 +1,7 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
 
 use hyper::Method;
 /// ServiceConf - reppresent the file servicedef.yaml as the configuration of the service
@@ -12,9 +15,42 @@ use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use serde::{Deserialize, Serialize};
 
-use crate::restmessage::RestMessage;
+use crate::restmessage::{RestMessage, percent_decode};
 
 use crate::procenv::ProcEnv;
+use crate::openapi::OpenApi;
+
+/// What to do with a process reply that does not match the OpenAPI responses
+#[derive(Serialize,Deserialize,Debug,Default,Clone,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidateOutPolicy {
+    /// log the violations and answer 502
+    #[default]
+    Reject,
+    /// log the violations, the reply pass through
+    Record,
+}
+
+/// How the process sends back the reply
+#[derive(Serialize,Deserialize,Debug,Default,Clone,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyMode {
+    /// POST on the backserv socket `/urhttp/{req_id}`
+    #[default]
+    Socket,
+    /// the process stdout, sent when the process exits
+    Stdout,
+}
+
+/// How a streamed reply is sent to the client
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    /// each chunk as it is, chunked transfer encoding
+    Chunked,
+    /// each chunk as a `text/event-stream` event, a `data:` field for each line
+    Sse,
+}
 
 #[derive(Serialize,Deserialize,Debug,Default,Clone)]
 pub struct VerbAction {
@@ -23,19 +59,122 @@ pub struct VerbAction {
     #[serde(default)]
     pub validateout: bool,
     #[serde(default)]
+    pub validateoutpolicy: ValidateOutPolicy,
+    #[serde(default)]
     pub logstdout: bool,
-    pub inject: Option<ProcEnv>
+    /// add the `urocket-stats` header (the process infos as JSON) to the reply
+    #[serde(default)]
+    pub stats: bool,
+    pub inject: Option<ProcEnv>,
+    /// request deadline in ms (default 40000), after it the client gets 504
+    pub deadline: Option<u64>,
+    /// reply at once `202 Accepted`, the result is polled on `GET /jobs/{req_id}`
+    #[serde(default, rename = "async")]
+    pub asyncjob: bool,
+    /// POST the result to this url (as `async`, the client gets `202 Accepted` at once)
+    pub callback: Option<String>,
+    /// the client can give the callback url by the `X-Urocket-Callback` header
+    #[serde(default)]
+    pub clientcallback: bool,
+    /// max request deadline in ms, the process can push back the deadline (and its
+    /// kill time) up to it by `/urhttp/{req_id}/extend`; not set, it can not
+    pub maxdeadline: Option<u64>,
+    /// max processes of the route running at once, the other requests are queued
+    pub maxconcurrent: Option<usize>,
+    /// the body of the 504 response sent when the deadline expires
+    pub timeoutbody: Option<String>,
+    #[serde(default)]
+    pub reply: ReplyMode,
+    /// stream the reply: the chunks POSTed on `/urhttp/{req_id}/chunk`,
+    /// or the stdout lines with `reply: stdout`
+    pub stream: Option<StreamFormat>,
+    /// parse the socket reply body as JSON and send it re-serialized,
+    /// as `application/json`: invalid JSON gives 502
+    #[serde(default)]
+    pub jsonreply: bool,
+    /// content type of the stdout reply, default `application/json`
+    /// (of a chunked stream without a content type, default `application/octet-stream`)
+    pub contenttype: Option<String>,
+    /// exit code to http status of the stdout reply:
+    /// when not listed 0 is 200, any other code is 500
+    #[serde(default)]
+    pub exitcodes: HashMap<i32, u16>,
 }
 
-#[derive(Serialize,Deserialize,Debug,Clone)]
+impl VerbAction {
+    /// The http status of the stdout reply for the process exit code
+    pub fn exit_status(&self, code: i32) -> u16 {
+        match self.exitcodes.get(&code) {
+            Some(status) => *status,
+            None if code == 0 => 200,
+            None => 500
+        }
+    }
+
+    /// Reject the settings that do not go together: a stream is sent to a waiting
+    /// client, an `async` or `callback` request has none
+    pub fn check(&self) -> Result<(), String> {
+        if self.stream.is_some() && (self.asyncjob || self.callback.is_some() || self.clientcallback) {
+            return Err(String::from("`stream` does not go with `async`, `callback` or `clientcallback`"));
+        }
+        match &self.inject {
+            Some(proce) => proce.check_pool(),
+            None => Ok(())
+        }
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,Default)]
 pub struct PathVerb {
     pub get: Option<VerbAction>,
     pub post: Option<VerbAction>,
-//    #[serde(rename="post")]
-//    Post{ validate_in: bool,
-//        inject: ProcEnv },
-//    Delete{ validate_in: bool,
-//        inject: ProcEnv },
+    pub put: Option<VerbAction>,
+    pub delete: Option<VerbAction>,
+    pub patch: Option<VerbAction>,
+    pub head: Option<VerbAction>,
+    pub options: Option<VerbAction>,
+}
+
+impl PathVerb {
+    /// The VerbAction configured for the http method
+    pub fn verb_action(&self, method: &Method) -> Option<&VerbAction> {
+        match *method {
+            Method::GET => self.get.as_ref(),
+            Method::POST => self.post.as_ref(),
+            Method::PUT => self.put.as_ref(),
+            Method::DELETE => self.delete.as_ref(),
+            Method::PATCH => self.patch.as_ref(),
+            Method::HEAD => self.head.as_ref(),
+            Method::OPTIONS => self.options.as_ref(),
+            _ => None
+        }
+    }
+
+    /// All the VerbActions configured for the path
+    pub fn verb_actions(&self) -> Vec<&VerbAction> {
+        [&self.get, &self.post, &self.put, &self.delete, &self.patch, &self.head, &self.options]
+        .into_iter()
+        .filter_map(|va| va.as_ref())
+        .collect()
+    }
+
+    /// The http methods configured for the path, as listed in the `Allow` header
+    pub fn allowed_methods(&self) -> Vec<Method> {
+        [Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::PATCH, Method::HEAD, Method::OPTIONS]
+        .into_iter()
+        .filter(|m| self.verb_action(m).is_some())
+        .collect()
+    }
+}
+
+/// The outcome of matching a RestMessage with the configured paths
+#[derive(Debug)]
+pub enum RouteMatch {
+    /// the VerbAction, the configured path and the parameters captured by it
+    Found { action: Box<VerbAction>, route: String, params: HashMap<String,String> },
+    /// the path is known, but not for this method: the allowed ones are listed
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
 }
 
 #[derive(Serialize,Deserialize,Debug)]
@@ -47,7 +186,35 @@ pub struct PathVerbT {
 pub struct ServiceConf {
     pub servicename: String,
     pub socketpath: String,
-    pub port: String,
+    /// the frontserv port, 0 means an ephemeral port
+    pub port: u16,
+    /// the frontserv bind addresses (IPv4 and/or IPv6), all IPv4 interfaces if empty
+    #[serde(default)]
+    pub bind: Vec<IpAddr>,
+    /// the OpenAPI document, relative to the configuration file directory
+    pub openapi: Option<String>,
+    /// the OpenAPI document loaded from `openapi`
+    #[serde(skip)]
+    pub openapidoc: Option<Arc<OpenApi>>,
+    /// debug mode: i.e. error responses include the process stderr
+    #[serde(default)]
+    pub debug: bool,
+    /// how long the results of the `async` requests are kept, in ms (default 600000)
+    pub jobttl: Option<u64>,
+    /// the key signing the callback deliveries (HMAC-SHA256), none: not signed
+    pub webhooksecret: Option<String>,
+    /// the callback delivery retries (default 5)
+    pub webhookretries: Option<u32>,
+    /// the delay before the first callback retry in ms (default 1000), doubled at each retry
+    pub webhookbackoff: Option<u64>,
+    /// max wait for the callback receiver answer in ms (default 10000), then the attempt failed
+    pub webhooktimeout: Option<u64>,
+    /// max processes running at once, the other requests are queued
+    pub maxprocesses: Option<usize>,
+    /// max requests waiting for a process slot (default 64), then 503
+    pub queuesize: Option<usize>,
+    /// max wait for a process slot in ms (default 10000), then 503
+    pub queuetimeout: Option<u64>,
     //pub paths: HashMap<String, serde_json::Value>
     pub paths: HashMap<String, PathVerb>
 }
@@ -69,7 +236,7 @@ async fn read_conf_file(conf_file: &str) -> String {
     }
 }
 
-use tracing::{info};
+use tracing::{info, warn};
 
 impl ServiceConf {
     pub async fn parse_service_def(configfilename: &str) -> ServiceConf {
@@ -77,8 +244,30 @@ impl ServiceConf {
         info!("READ {} bytes from conf file {}", content.len(), &configfilename);
 
         match serde_yaml::from_str::<ServiceConf>(&content) {
-            Ok(s) => {
+            Ok(mut s) => {
                 info!("parsed {:?}",&s);
+                if let Some(openapi) = &s.openapi {
+                    let dir = Path::new(configfilename).parent().unwrap_or(Path::new(""));
+                    let openapifile = dir.join(openapi).to_string_lossy().to_string();
+                    match OpenApi::load(&openapifile).await {
+                        Ok(doc) => {
+                            info!("loaded {:?} from {}", doc, openapifile);
+                            s.openapidoc = Some(Arc::new(doc));
+                        }
+                        Err(e) => {
+                            panic!("\nPANIC Error reading OpenAPI document \n\nfile:{} > {e}\n", configfilename);
+                        }
+                    }
+                } else if s.paths.values().flat_map(|pv| pv.verb_actions()).any(|va| va.validatein || va.validateout) {
+                    warn!("validatein/validateout are set, but there is no `openapi` document: nothing is validated");
+                }
+                for (path, pv) in s.paths.iter() {
+                    for va in pv.verb_actions() {
+                        if let Err(e) = va.check() {
+                            panic!("\nPANIC Error in configuration \n\nfile:{} path:{} > {e}\n", configfilename, path);
+                        }
+                    }
+                }
                 s
             },
             Err(e) => {
@@ -86,24 +275,72 @@ impl ServiceConf {
             }
         }
     }
-    pub fn match_request(&self, rm: &RestMessage) -> Option<VerbAction> {
-        if let Some(pv) = self.paths.get(rm.uri()) {
-            let method = rm.method();
-            match method {
-                &Method::GET => {
-                    pv.get.clone()
-                }
-                &Method::POST => {
-                    pv.post.clone()
-                },
-                _ => None
+
+    /// Find the PathVerb configured for `uri`: an exact match is tried first,
+    /// then the templated paths (i.e. `/pet/{petId}/uploadImage`).
+    /// When more than one template matches, literal segments win over
+    /// templated ones (from left to right).
+    /// Returns the matching path and the captured path parameters too.
+    pub fn match_path(&self, uri: &str) -> Option<(&String, &PathVerb, HashMap<String,String>)> {
+        if let Some((template, pv)) = self.paths.get_key_value(uri) {
+            return Some((template, pv, HashMap::new()));
+        }
+        self.paths.iter()
+        .filter_map(|(template, pv)| {
+            match_template(template, uri).map(|(rank, params)| (rank, template, pv, params))
+        })
+        .max_by(|a, b| {
+            // same rank: keep the choice stable, whatever the HashMap order
+            a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1))
+        })
+        .map(|(_, template, pv, params)| (template, pv, params))
+    }
+
+    /// Match the request against the configured paths: returns the VerbAction
+    /// and the path parameters captured by the path template, or tells
+    /// if the path is unknown or the method is not configured for it
+    pub fn match_request(&self, rm: &RestMessage) -> RouteMatch {
+        if let Some((route, pv, params)) = self.match_path(rm.uri()) {
+            match pv.verb_action(rm.method()) {
+                Some(va) => RouteMatch::Found { action: Box::new(va.clone()), route: route.clone(), params },
+                None => RouteMatch::MethodNotAllowed(pv.allowed_methods())
+            }
+        } else {
+            RouteMatch::NotFound
+        }
+    }
+}
+
+fn is_template_segment(segment: &str) -> bool {
+    segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+/// Match `uri` against the path `template`, segment by segment.
+/// On success returns, for each segment, true if it was matched literally
+/// (this is the rank of the match) and the captured parameters
+/// (percent-decoded).
+fn match_template(template: &str, uri: &str) -> Option<(Vec<bool>, HashMap<String,String>)> {
+    let tsegs: Vec<&str> = template.split('/').collect();
+    let usegs: Vec<&str> = uri.split('/').collect();
+    if tsegs.len() != usegs.len() {
+        return None;
+    }
+    let mut rank = Vec::with_capacity(tsegs.len());
+    let mut params = HashMap::new();
+    for (t, u) in tsegs.iter().zip(usegs.iter()) {
+        if is_template_segment(t) {
+            if u.is_empty() {
+                return None;
             }
+            params.insert(t[1..t.len()-1].to_string(), percent_decode(u));
+            rank.push(false);
+        } else if t == u {
+            rank.push(true);
         } else {
-            None
+            return None;
         }
-        //None
-        //rm.uri()
     }
+    Some((rank, params))
 }
 
 
@@ -117,6 +354,81 @@ mod tests {
         let serviceconf = ServiceConf::parse_service_def(configfilename).await;
         println!("{:?}",serviceconf);
         assert_eq!(serviceconf.servicename, "mynastyphpport");
+        assert_eq!(serviceconf.port, 8080);
+        assert!(serviceconf.openapidoc.unwrap().operation("/pet/{petId}", &Method::GET).is_some());
+    }
+
+    #[test]
+    fn verb_action_check() {
+        let yaml = "inject:\n  wd: /tmp\n  env: []\n  cmd: !ToSplit ./export.sh\nstream: sse\n";
+        let va: VerbAction = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(va.check(), Ok(()));
+        for extra in ["async: true\n", "callback: http://hooks.example.com/done\n", "clientcallback: true\n"] {
+            let va: VerbAction = serde_yaml::from_str(&format!("{yaml}{extra}")).unwrap();
+            assert_eq!(va.check(), Err(String::from("`stream` does not go with `async`, `callback` or `clientcallback`")));
+        }
+        let va: VerbAction = serde_yaml::from_str("inject:\n  wd: /tmp\n  env: []\n  cmd: !ToSplit cat\n  pool:\n    workers: 2\n  maxoutput: 100\n").unwrap();
+        assert_eq!(va.check(), Err(String::from("`pool` does not support maxoutput")));
+    }
+
+    fn conf_with_paths(paths: &[&str]) -> ServiceConf {
+        let mut conf = ServiceConf::default();
+        for p in paths {
+            let va = VerbAction { inject: Some(ProcEnv::new("", vec![], p, "")), ..Default::default() };
+            conf.paths.insert(p.to_string(), PathVerb { get: Some(va), ..Default::default() });
+        }
+        conf
+    }
+
+    fn matched_cmd(conf: &ServiceConf, uri: &str) -> Option<(String, HashMap<String,String>)> {
+        let rm = RestMessage::new("get", uri, "");
+        match conf.match_request(&rm) {
+            RouteMatch::Found { action, route, params } => {
+                let cmd = action.inject.unwrap().cmd_to_arr_replacements(&HashMap::new()).join(" ");
+                assert_eq!(cmd, route);
+                Some((cmd, params))
+            }
+            _ => None
+        }
+    }
+
+    #[test]
+    fn match_templated_path() {
+        let conf = conf_with_paths(&["/pet/{petId}/uploadImage", "/pet/{petId}"]);
+        let (cmd, params) = matched_cmd(&conf, "/pet/12/uploadImage").unwrap();
+        assert_eq!(cmd, "/pet/{petId}/uploadImage");
+        assert_eq!(params.get("petId").unwrap(), "12");
+        let (cmd, params) = matched_cmd(&conf, "/pet/a%20cat").unwrap();
+        assert_eq!(cmd, "/pet/{petId}");
+        assert_eq!(params.get("petId").unwrap(), "a cat");
+        assert!(matched_cmd(&conf, "/pet/").is_none());
+        assert!(matched_cmd(&conf, "/pet/12/other").is_none());
+    }
+
+    #[test]
+    fn match_literal_wins() {
+        let conf = conf_with_paths(&["/pet/{petId}", "/pet/findByStatus", "/{kind}/findByStatus"]);
+        let (cmd, params) = matched_cmd(&conf, "/pet/findByStatus").unwrap();
+        assert_eq!(cmd, "/pet/findByStatus");
+        assert!(params.is_empty());
+        let (cmd, params) = matched_cmd(&conf, "/dog/findByStatus").unwrap();
+        assert_eq!(cmd, "/{kind}/findByStatus");
+        assert_eq!(params.get("kind").unwrap(), "dog");
+    }
+
+    #[test]
+    fn match_method_not_allowed() {
+        let mut conf = conf_with_paths(&["/pet/{petId}"]);
+        conf.paths.get_mut("/pet/{petId}").unwrap().delete = Some(VerbAction::default());
+        let rm = RestMessage::new("delete", "/pet/12", "");
+        assert!(matches!(conf.match_request(&rm), RouteMatch::Found { .. }));
+        let rm = RestMessage::new("put", "/pet/12", "");
+        match conf.match_request(&rm) {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, vec![Method::GET, Method::DELETE]),
+            other => panic!("unexpected {:?}", other)
+        }
+        let rm = RestMessage::new("get", "/dog/12", "");
+        assert!(matches!(conf.match_request(&rm), RouteMatch::NotFound));
     }
 
 }
\ No newline at end of file
@@ -13,7 +13,7 @@ use tokio::sync::Mutex;
  * to RabbitMQ service (whose parameters are extracted form cmdline, env)
  */
 
-use crate::{serviceconf::{ServiceConf, VerbAction}, restmessage::RestMessage};
+use crate::{serviceconf::{ServiceConf, RouteMatch}, restmessage::RestMessage};
 
 #[derive(Debug)]
 pub enum UCommands {
@@ -52,11 +52,11 @@ impl URConfig {
         }
     }
 
-    pub fn match_request(&self, rm: &RestMessage) -> Option<VerbAction> {
+    pub fn match_request(&self, rm: &RestMessage) -> RouteMatch {
         if let Some(conf) = &self.serviceconf {
             conf.match_request(rm)
         } else {
-            None
+            RouteMatch::NotFound
         }
     }
 
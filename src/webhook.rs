@@ -0,0 +1,196 @@
+//! Delivery of the results to a callback url: the reply of the process is
+//! POSTed to the url once the process exited, retried with exponential
+//! backoff until the receiver answers 2xx. With a secret the timestamp and
+//! the body are signed (HMAC-SHA256) in the
+//! `X-Urocket-Signature: t=<ts>,sha256=<hex>` header.
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::{Method, Request, Uri};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use sha2::Sha256;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+use crate::processcontroller::ProcessExit;
+use crate::requestsvisor::ForHttpResponse;
+
+/// the callback url header, when the route allows it (`clientcallback`)
+pub const CALLBACK_HEADER: &str = "x-urocket-callback";
+pub const SIGNATURE_HEADER: &str = "x-urocket-signature";
+/// the unix time (s) of the delivery attempt, signed with the body
+pub const TIMESTAMP_HEADER: &str = "x-urocket-timestamp";
+pub const DEFAULT_RETRIES: u32 = 5;
+/// delay before the first retry in ms, doubled at each retry
+pub const DEFAULT_BACKOFF: u64 = 1000;
+/// max wait for the receiver answer in ms, then the attempt failed
+pub const DEFAULT_DELIVERY_TIMEOUT: u64 = 10000;
+
+#[derive(Debug,Clone)]
+pub struct Webhook {
+    url: Uri,
+    secret: Option<String>,
+    retries: u32,
+    backoff: u64,
+    timeout: u64,
+}
+
+impl Webhook {
+    /// Only `http://` urls are supported
+    pub fn new(url: &str, secret: Option<String>, retries: u32, backoff: u64, timeout: u64) -> Result<Self, String> {
+        let url: Uri = url.parse().map_err(|e| format!("invalid callback url {}: {}", url, e))?;
+        if url.scheme_str() != Some("http") || url.host().is_none() {
+            return Err(format!("invalid callback url {}: http://host/... expected", url));
+        }
+        Ok(Webhook { url, secret, retries, backoff, timeout })
+    }
+
+    /// POST the reply for the request id: its body, its headers, the status code
+    /// in `X-Urocket-Status` and the process exit in `X-Urocket-Exit-Code`
+    /// (`X-Urocket-Exit-Signal`). Retries on errors, timeouts and non 2xx
+    /// responses. Returns true when delivered
+    pub async fn deliver(&self, req_id: &str, reply: &ForHttpResponse, exit: Option<&ProcessExit>) -> bool {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let mut delay = self.backoff;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                delay = delay.saturating_mul(2);
+            }
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .uri(self.url.clone())
+                .header("x-urocket-request-id", req_id)
+                .header("x-urocket-status", reply.code);
+            for (name, value) in reply.headers.iter().filter(|(name, _)| !name.eq_ignore_ascii_case("content-length")) {
+                builder = builder.header(name.as_str(), value.as_str());
+            }
+            if let Some(code) = exit.and_then(|exit| exit.code) {
+                builder = builder.header("x-urocket-exit-code", code);
+            }
+            if let Some(signal) = exit.and_then(|exit| exit.signal) {
+                builder = builder.header("x-urocket-exit-signal", signal);
+            }
+            if let Some(secret) = &self.secret {
+                let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+                builder = builder
+                    .header(TIMESTAMP_HEADER, timestamp)
+                    .header(SIGNATURE_HEADER, signature(secret, timestamp, &reply.body));
+            }
+            let request = match builder.body(Full::new(reply.body.clone())) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("request {} not delivered to {}, invalid reply headers: {}", req_id, self.url, e);
+                    return false;
+                }
+            };
+            match tokio::time::timeout(Duration::from_millis(self.timeout), client.request(request)).await {
+                Ok(Ok(response)) if response.status().is_success() => {
+                    info!("request {} delivered to {}", req_id, self.url);
+                    return true;
+                }
+                Ok(Ok(response)) => warn!("request {} delivery to {} failed (attempt {}): {}", req_id, self.url, attempt + 1, response.status()),
+                Ok(Err(e)) => warn!("request {} delivery to {} failed (attempt {}): {}", req_id, self.url, attempt + 1, e),
+                Err(_) => warn!("request {} delivery to {} failed (attempt {}): no answer in {}ms", req_id, self.url, attempt + 1, self.timeout),
+            }
+        }
+        warn!("request {} not delivered to {}, giving up", req_id, self.url);
+        false
+    }
+}
+
+/// `t=<timestamp>,sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`: the receiver
+/// rejects the old timestamps, so a captured delivery can not be replayed
+pub fn signature(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let signed = [timestamp.to_string().as_bytes(), b".", body].concat();
+    format!("t={},sha256={}", timestamp, hmac_hex(secret, &signed))
+}
+
+fn hmac_hex(secret: &str, data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// read the headers and the content-length body
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head.lines()
+                    .filter_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                    .next().unwrap_or(0);
+                if body.len() >= length || n == 0 {
+                    return text;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hmac_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hmac_hex("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(signature("Jefe", 1700000000, b"{}"), format!("t=1700000000,sha256={}", hmac_hex("Jefe", b"1700000000.{}")));
+        assert!(Webhook::new("https://example.com/hook", None, 1, 1, 1).is_err());
+        assert!(Webhook::new("/hook", None, 1, 1, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn deliver_with_retries() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let seen = received.clone();
+        tokio::spawn(async move {
+            // the first attempt gets no answer, the second fails with 500, the third is accepted
+            let (mut silent, _) = listener.accept().await.unwrap();
+            let request = read_request(&mut silent).await;
+            seen.lock().unwrap().push(request);
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                seen.lock().unwrap().push(request);
+                let reply = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+            drop(silent);
+        });
+        let hook = Webhook::new(&format!("http://{}/hook", addr), Some(String::from("secret")), 2, 10, 200).unwrap();
+        let reply = ForHttpResponse {
+            code: 201,
+            body: Bytes::from("{\"status\":\"done\"}"),
+            headers: vec![(String::from("content-type"), String::from("application/json"))],
+        };
+        let exit = ProcessExit { code: Some(0), ..Default::default() };
+        assert!(hook.deliver("JOB-1", &reply, Some(&exit)).await);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        let request = received[2].to_lowercase();
+        assert!(request.starts_with("post /hook"));
+        assert!(request.contains("x-urocket-request-id: job-1"));
+        assert!(request.contains("x-urocket-status: 201"));
+        assert!(request.contains("x-urocket-exit-code: 0"));
+        assert!(request.contains("content-type: application/json"));
+        let timestamp: u64 = request.lines()
+            .find_map(|l| l.strip_prefix("x-urocket-timestamp: "))
+            .unwrap().trim().parse().unwrap();
+        assert!(request.contains(&format!("x-urocket-signature: {}", signature("secret", timestamp, b"{\"status\":\"done\"}"))));
+        assert!(request.ends_with("{\"status\":\"done\"}"));
+    }
+}
@@ -0,0 +1,331 @@
+//! Persistent pre-started workers (`pool` in `inject`): instead of a new
+//! process per request, `workers` long-lived processes get one request per
+//! line on stdin, as JSON:
+//!
+//!   {"req_id": "...", "method": "POST", "path": "/pets?x=1", "route": "/pets",
+//!    "params": {}, "query": "x=1", "headers": {...}, "body": "..."}
+//!
+//! (a body not in UTF-8 is base64 encoded, with `"bodyencoding": "base64"`).
+//! The worker answers with one line on stdout when done with the request:
+//! `{"exitcode": 0, "body": ...}` is the `reply: stdout` reply (a line not in
+//! JSON is the body itself), with `reply: socket` the worker replies through
+//! the backserv socket as usual and the line just acknowledges the request.
+//! A worker is recycled after `maxrequests` requests, over `maxmemory`, when
+//! it crashes or when it does not answer before the kill time.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+use base64::Engine;
+use bytes::Bytes;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{mpsc, oneshot, Mutex as TMutex};
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+use crate::procenv::{PoolConf, ProcEnv};
+use crate::processcontroller::{get_now_ms, kill_time, ProcessExit};
+use crate::restmessage::RestMessage;
+
+/// delay before restarting a worker that could not be started
+const RESTART_DELAY: u64 = 1000;
+/// time a recycled worker has to exit after its stdin is closed, then it is killed
+const RETIRE_GRACE: u64 = 2000;
+
+/// A request for the pool
+pub struct PoolJob {
+    pub uuid: String,
+    /// the request JSON line, see `request_line`
+    pub request: String,
+    /// the kill time (ms since epoch), it can be pushed back meanwhile
+    pub kill_at: Arc<AtomicU64>,
+    pub done: oneshot::Sender<WorkerDone>,
+}
+
+/// The outcome of a request served by a worker
+#[derive(Debug)]
+pub struct WorkerDone {
+    pub exit: ProcessExit,
+    pub pid: u32,
+    pub start_ms: u128,
+    pub stop_ms: u128,
+}
+
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: mpsc::UnboundedSender<PoolJob>,
+}
+
+impl WorkerPool {
+    /// Start the workers, they serve the submitted jobs first come first served
+    pub fn start(proce: &ProcEnv) -> Self {
+        let conf = proce.pool.clone().unwrap_or_default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rx = Arc::new(TMutex::new(rx));
+        for n in 0..conf.workers.max(1) {
+            tokio::spawn(worker_loop(n, proce.clone(), conf.clone(), rx.clone()));
+        }
+        WorkerPool { jobs: tx }
+    }
+
+    pub fn submit(&self, job: PoolJob) {
+        if let Err(e) = self.jobs.send(job) {
+            let job = e.0;
+            let _ = job.done.send(WorkerDone {
+                exit: ProcessExit { error: Some(String::from("worker pool closed")), ..Default::default() },
+                pid: 0,
+                start_ms: 0,
+                stop_ms: 0,
+            });
+        }
+    }
+}
+
+/// The JSON line describing the request for the workers
+pub fn request_line(uuid: &str, req: &RestMessage) -> String {
+    let headers: serde_json::Map<String, Value> = req.headers().keys()
+        .filter_map(|name| req.header(name.as_str()).map(|v| (name.to_string(), Value::String(v))))
+        .collect();
+    let mut line = json!({
+        "req_id": uuid,
+        "method": req.method().as_str(),
+        "path": req.uri(),
+        "route": req.route(),
+        "params": req.path_params(),
+        "query": req.query(),
+        "headers": headers,
+    });
+    match std::str::from_utf8(req.body()) {
+        Ok(text) => line["body"] = Value::String(text.to_string()),
+        Err(_) => {
+            line["body"] = Value::String(base64::engine::general_purpose::STANDARD.encode(req.body()));
+            line["bodyencoding"] = Value::String(String::from("base64"));
+        }
+    }
+    line.to_string()
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    pid: u32,
+    served: u64,
+}
+
+impl Worker {
+    fn start(proce: &ProcEnv, n: usize) -> std::io::Result<Worker> {
+        let cmd_and_args = proce.cmd_to_arr_replacements(&Default::default());
+        let mut cmd = Command::new(&cmd_and_args[0]);
+        if let Some(inherited) = proce.inherited_env() {
+            cmd.env_clear().envs(inherited);
+        }
+        if let Some(wd) = proce.work_dir().map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))? {
+            cmd.current_dir(wd);
+        }
+        cmd.args(&cmd_and_args[1..])
+            .envs(proce.get_env())
+            .env("UROCKET_WORKER", n.to_string())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = cmd.spawn()?;
+        let pid = child.id().unwrap_or(0);
+        let stdin = child.stdin.take().expect("Internal error, could not take stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("Internal error, could not take stdout")).lines();
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    info!("worker Pid({pid}) stderr: {}", line);
+                }
+            });
+        }
+        info!("worker {} started Pid({pid})", n);
+        Ok(Worker { child, stdin, stdout, pid, served: 0 })
+    }
+
+    /// Write the request, read the answer line
+    async fn serve(&mut self, request: &str) -> std::io::Result<Option<String>> {
+        self.stdin.write_all(request.as_bytes()).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await?;
+        self.stdout.next_line().await
+    }
+
+    /// Resident memory in KB, from /proc
+    fn rss(&self) -> Option<u64> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.pid)).ok()?;
+        status.lines()
+            .find_map(|l| l.strip_prefix("VmRSS:"))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse().ok())
+    }
+
+    fn worn_out(&self, conf: &PoolConf) -> bool {
+        conf.maxrequests.is_some_and(|max| self.served >= max)
+            || conf.maxmemory.is_some_and(|max| self.rss().is_some_and(|rss| rss > max))
+    }
+
+    /// Close stdin and let the worker exit, killed after RETIRE_GRACE
+    fn retire(self) {
+        let Worker { mut child, stdin, pid, .. } = self;
+        drop(stdin);
+        tokio::spawn(async move {
+            match tokio::time::timeout(Duration::from_millis(RETIRE_GRACE), child.wait()).await {
+                Ok(status) => info!("worker Pid({pid}) retired: {:?}", status),
+                Err(_) => {
+                    warn!("worker Pid({pid}) did not exit, killed");
+                    let _ = child.kill().await;
+                }
+            }
+        });
+    }
+}
+
+/// The ProcessExit of a `{"exitcode": .., "body": ..}` answer, a line not in JSON is the body
+fn answer_exit(line: String) -> ProcessExit {
+    let (code, stdout) = match serde_json::from_str::<Value>(&line) {
+        Ok(Value::Object(answer)) => {
+            let code = answer.get("exitcode").and_then(|c| c.as_i64()).unwrap_or(0) as i32;
+            let body = match answer.get("body") {
+                Some(Value::String(body)) => body.clone(),
+                Some(Value::Null) | None => String::new(),
+                Some(body) => body.to_string(),
+            };
+            (code, body)
+        }
+        _ => (0, line),
+    };
+    ProcessExit { code: Some(code), stdout_raw: Bytes::from(stdout.clone()), stdout, ..Default::default() }
+}
+
+async fn worker_loop(n: usize, proce: ProcEnv, conf: PoolConf, jobs: Arc<TMutex<mpsc::UnboundedReceiver<PoolJob>>>) {
+    let mut worker: Option<Worker> = None;
+    loop {
+        if worker.is_none() {
+            match Worker::start(&proce, n) {
+                Ok(w) => worker = Some(w),
+                Err(e) => {
+                    warn!("worker {} start ERROR {}", n, e);
+                    // the next request fails, as a process that can not be spawned
+                    let Some(job) = jobs.lock().await.recv().await else { return };
+                    let now = get_now_ms();
+                    let exit = ProcessExit { error: Some(format!("worker start error: {}", e)), ..Default::default() };
+                    let _ = job.done.send(WorkerDone { exit, pid: 0, start_ms: now, stop_ms: now });
+                    tokio::time::sleep(Duration::from_millis(RESTART_DELAY)).await;
+                    continue;
+                }
+            }
+        }
+        let Some(job) = jobs.lock().await.recv().await else { return };
+        let Some(mut w) = worker.take() else { continue };
+        let pid = w.pid;
+        let start_ms = get_now_ms();
+        let answer = tokio::select! {
+            answer = w.serve(&job.request) => Some(answer),
+            _ = kill_time(&job.kill_at) => None,
+        };
+        let exit = match answer {
+            Some(Ok(Some(line))) => {
+                w.served += 1;
+                let exit = answer_exit(line);
+                if w.worn_out(&conf) {
+                    info!("worker Pid({}) recycled after {} requests", w.pid, w.served);
+                    w.retire();
+                } else {
+                    worker = Some(w);
+                }
+                exit
+            }
+            Some(answer) => {
+                // stdout closed or broken pipe: the worker is gone
+                let status = tokio::time::timeout(Duration::from_millis(RETIRE_GRACE), w.child.wait()).await;
+                let (code, signal) = match status {
+                    Ok(Ok(status)) => (status.code(), std::os::unix::process::ExitStatusExt::signal(&status)),
+                    _ => (None, None),
+                };
+                warn!("worker Pid({}) crashed serving {}: {:?} {:?}", w.pid, job.uuid, answer.err(), status);
+                ProcessExit {
+                    code,
+                    signal,
+                    error: Some(String::from("the worker exited while serving the request")),
+                    ..Default::default()
+                }
+            }
+            None => {
+                warn!("worker Pid({}) killed, {} timed out", w.pid, job.uuid);
+                let _ = w.child.kill().await;
+                ProcessExit { signal: Some(libc::SIGKILL), was_killed: true, ..Default::default() }
+            }
+        };
+        let _ = job.done.send(WorkerDone { exit, pid, start_ms, stop_ms: get_now_ms() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool_env(script: &str, conf: PoolConf) -> ProcEnv {
+        let mut proce = ProcEnv::new_v("", vec![], &["/bin/sh", "-c", script], "");
+        proce.pool = Some(conf);
+        proce
+    }
+
+    async fn submit(pool: &WorkerPool, uuid: &str, kill_in: u64) -> WorkerDone {
+        let (tx, rx) = oneshot::channel();
+        let req = RestMessage::new("POST", "/echo", "hello");
+        pool.submit(PoolJob {
+            uuid: uuid.to_string(),
+            request: request_line(uuid, &req),
+            kill_at: Arc::new(AtomicU64::new(get_now_ms() as u64 + kill_in)),
+            done: tx,
+        });
+        rx.await.unwrap()
+    }
+
+    #[test]
+    fn worker_answers() {
+        let exit = answer_exit(String::from("{\"exitcode\": 4, \"body\": {\"ok\": true}}"));
+        assert_eq!((exit.code, exit.stdout.as_str()), (Some(4), "{\"ok\":true}"));
+        let exit = answer_exit(String::from("plain text"));
+        assert_eq!((exit.code, exit.stdout.as_str()), (Some(0), "plain text"));
+        let line: Value = serde_json::from_str(&request_line("R1", &RestMessage::new("POST", "/echo", "hi"))).unwrap();
+        assert_eq!(line["req_id"], "R1");
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["body"], "hi");
+    }
+
+    #[tokio::test]
+    async fn pool_recycle() {
+        // answers with its pid, so the recycled worker is recognizable
+        let script = "while read line; do echo \"{\\\"body\\\": \\\"$$\\\"}\"; done";
+        let pool = WorkerPool::start(&pool_env(script, PoolConf { workers: 1, maxrequests: Some(2), maxmemory: None }));
+        let first = submit(&pool, "R1", 2000).await;
+        let second = submit(&pool, "R2", 2000).await;
+        let third = submit(&pool, "R3", 2000).await;
+        assert_eq!(first.exit.code, Some(0));
+        assert_eq!(first.exit.stdout, second.exit.stdout);
+        assert_ne!(second.exit.stdout, third.exit.stdout);
+    }
+
+    #[tokio::test]
+    async fn pool_crash_and_timeout() {
+        // serves one request, then exits; a request with "slow" is never answered
+        let script = "read line; case \"$line\" in *slow*) sleep 10;; *) echo '{\"body\": \"once\"}';; esac; exit 3";
+        let pool = WorkerPool::start(&pool_env(script, PoolConf { workers: 1, ..Default::default() }));
+        let done = submit(&pool, "R1", 2000).await;
+        assert_eq!(done.exit.stdout, "once");
+        let done = submit(&pool, "R2", 2000).await;
+        assert!(done.exit.error.is_some());
+        assert_eq!(done.exit.code, Some(3));
+        let done = submit(&pool, "slow", 200).await;
+        assert!(done.exit.was_killed);
+        assert!(done.stop_ms - done.start_ms < 2000);
+        let done = submit(&pool, "R4", 2000).await;
+        assert_eq!(done.exit.stdout, "once");
+    }
+}